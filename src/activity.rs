@@ -0,0 +1,141 @@
+//! Tracks which chunks of a map are changing, so that quiescent regions can be skipped by the
+//! simulation and by the renderer.
+//!
+//! A chunk is active while any of its tiles, or those of a neighbouring chunk, changed by
+//! more than `THRESHOLD` of the largest value of their field in the last update or were
+//! written to directly. Chunks that changed are also flagged dirty, until the flags are
+//! cleared by whoever redraws them. The activity is saved along with the map, so that a
+//! loaded world carries on as the saved one would have.
+
+use std::collections::BTreeSet;
+
+use world;
+
+/// Smallest change of a tile value that keeps its chunk active, relative to the largest
+/// magnitude of its field, so that fields behave the same whatever their scale.
+pub const THRESHOLD: f32 = 1e-6;
+/// Fraction of the chunks that, once active, makes updating all of them cheaper than
+/// keeping track of which ones to update.
+pub const FULL_UPDATE_FRACTION: f32 = 0.5;
+
+/// An `Activity` that hasn't been started, e.g. the default one, treats every chunk as active
+/// and dirty.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Activity
+{
+    started: bool,
+    active: BTreeSet<world::ChunkPosition>,
+    dirty: BTreeSet<world::ChunkPosition>,
+    /// Chunks marked since the last update, to keep repeated writes cheap.
+    marked: BTreeSet<world::ChunkPosition>,
+}
+
+impl Activity
+{
+    /// Starts out with every one of `chunks` active and dirty.
+    pub fn new<I: Iterator<Item = world::ChunkPosition>>(chunks: I) -> Self
+    {
+        let active: BTreeSet<world::ChunkPosition> = chunks.collect();
+        Self
+        {
+            started: true,
+            dirty: active.clone(),
+            active,
+            marked: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_started(&self) -> bool
+    {
+        self.started
+    }
+
+    pub fn is_active(&self, chunk: world::ChunkPosition) -> bool
+    {
+        self.active.contains(&chunk)
+    }
+
+    /// Whether more than `FULL_UPDATE_FRACTION` of `chunks` are active.
+    pub fn is_mostly_active<'a, I: Iterator<Item = &'a world::ChunkPosition>>(&self, chunks: I) -> bool
+    {
+        let (active, total) = chunks.fold((0, 0), |(active, total), chunk|
+            (active + self.is_active(*chunk) as usize, total + 1));
+        active as f32 > FULL_UPDATE_FRACTION * total as f32
+    }
+
+    /// Flags `chunk` as changed from outside the simulation.
+    pub fn mark(&mut self, chunk: world::ChunkPosition) -> ()
+    {
+        if self.marked.insert(chunk)
+        {
+            self.activate_around(chunk);
+            self.dirty.insert(chunk);
+        }
+    }
+
+    /// Chunks changed since the last `clear_dirty`, or `None` if that isn't known.
+    pub fn dirty_chunks(&self) -> Option<&BTreeSet<world::ChunkPosition>>
+    {
+        if self.started { Some(&self.dirty) } else { None }
+    }
+
+    pub fn clear_dirty(&mut self) -> ()
+    {
+        self.dirty.clear();
+    }
+
+    /// Makes `changed` and its neighbours the active chunks.
+    pub fn update(&mut self, changed: &[world::ChunkPosition]) -> ()
+    {
+        self.active.clear();
+        self.marked.clear();
+        for &chunk in changed.iter()
+        {
+            self.dirty.insert(chunk);
+            self.activate_around(chunk);
+        }
+    }
+
+    /// Keeps `chunk` active until the next update, without flagging it as changed.
+    pub fn activate(&mut self, chunk: world::ChunkPosition) -> ()
+    {
+        self.active.insert(chunk);
+    }
+
+    /// Flags a chunk that was unloaded, so that it is redrawn.
+    pub fn unload(&mut self, chunk: world::ChunkPosition) -> ()
+    {
+        self.active.remove(&chunk);
+        self.dirty.insert(chunk);
+    }
+
+    /// Activates `chunk` and its neighbours, whose border tiles average over it.
+    fn activate_around(&mut self, chunk: world::ChunkPosition) -> ()
+    {
+        for y in (chunk[1] - 1)..(chunk[1] + 2)
+        {
+            for x in (chunk[0] - 1)..(chunk[0] + 2)
+            {
+                self.active.insert([x, y]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn mostly_active_past_the_fraction() -> ()
+    {
+        let chunks: Vec<world::ChunkPosition> = (0..10).map(|x| [x * 3, 0]).collect();
+        let mut activity = Activity::new(chunks.iter().cloned());
+        assert!(activity.is_mostly_active(chunks.iter()));
+        activity.update(&chunks[..5]);
+        assert!(!activity.is_mostly_active(chunks.iter()));
+        activity.update(&chunks[..6]);
+        assert!(activity.is_mostly_active(chunks.iter()));
+    }
+}
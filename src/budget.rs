@@ -0,0 +1,166 @@
+//! Bookkeeping of how much of every field a step adds to the map and takes from it, to check
+//! that the simulation conserves what it should and to measure the throughput of drains.
+//!
+//! The quantity of a field is the sum of its values over the empty tiles of the map. Drains,
+//! sources and fixed-value edges hold their values whatever diffuses in or out of them, so
+//! they are reservoirs outside of it. Tallies are kept in `f64` so that small exchanges
+//! aren't lost against large totals.
+
+use std::collections::BTreeMap;
+
+use world;
+
+/// What happened to the quantity of one field during a step.
+#[derive(Clone, Debug, Default)]
+pub struct Budget
+{
+    /// Quantity at the end of the previous step.
+    pub initial: f64,
+    /// Quantity at the end of the step.
+    pub total: f64,
+    /// Added by brushes since the previous step, including what painting over empty tiles
+    /// took away.
+    pub brushed: f64,
+    /// Added by emitters, net of what sinks took.
+    pub fixtures: f64,
+    /// Diffused out of source tiles, net of what diffused into them.
+    pub sources: f64,
+    /// Diffused in across fixed-value edges of the map, net of what diffused out.
+    pub boundaries: f64,
+    /// Diffused or carried by the flow into every drain, by the position of the drain tile.
+    pub drains: BTreeMap<world::Point, f64>,
+    /// Carried by the flow across the edges of the map, net of what it carries out, and
+    /// taken away by the decay of pressure values.
+    pub flow: f64,
+    /// Left in chunks unloaded for having stayed blank, whose tiles held no more than the
+    /// activity threshold.
+    pub unloaded: f64,
+    /// Change caused by entities pushing the field along, which is zero but for rounding.
+    pub entities: f64,
+}
+
+impl Budget
+{
+    /// Diffused into all drains together.
+    pub fn drained(&self) -> f64
+    {
+        self.drains.values().fold(0.0, |drained, &amount| drained + amount)
+    }
+
+    /// Change of the quantity that no tally accounts for, which is only rounding error for
+    /// a conserving step. Changes made other than through the world's brushes show up here.
+    pub fn residual(&self) -> f64
+    {
+        self.total - self.initial -
+        (self.brushed + self.fixtures + self.sources + self.boundaries - self.drained() + self.flow -
+         self.unloaded + self.entities)
+    }
+
+    pub fn csv_header() -> &'static str
+    {
+        "initial,total,brushed,fixtures,sources,boundaries,drained,flow,unloaded,entities,residual"
+    }
+
+    pub fn to_csv(&self) -> String
+    {
+        format!("{},{},{},{},{},{},{},{},{},{},{}", self.initial, self.total, self.brushed, self.fixtures,
+                self.sources, self.boundaries, self.drained(), self.flow, self.unloaded, self.entities,
+                self.residual())
+    }
+
+    /// Adds the exchanges tallied by `other`, leaving the totals.
+    pub fn add(&mut self, other: &Budget) -> ()
+    {
+        self.brushed += other.brushed;
+        self.fixtures += other.fixtures;
+        self.sources += other.sources;
+        self.boundaries += other.boundaries;
+        for (&position, &amount) in other.drains.iter()
+        {
+            *self.drains.entry(position).or_insert(0.0) += amount;
+        }
+        self.flow += other.flow;
+        self.unloaded += other.unloaded;
+        self.entities += other.entities;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use world::{World, Tile, Transport, Boundary};
+
+    fn drained_world(transport: Transport) -> World
+    {
+        let mut world = World::new(60, 40);
+        world.parameters.transport = transport;
+        world.brush(Tile::Empty(1.0), [20, 20], 8.0);
+        *world.at_mut([28, 20]).unwrap() = Tile::Drain;
+        *world.at_mut([40, 10]).unwrap() = Tile::Drain;
+        *world.at_mut([10, 30]).unwrap() = Tile::Emitter(2.0);
+        world
+    }
+
+    /// What the drain at `[28, 20]` took over the steps.
+    fn check_conservation(mut world: World) -> f64
+    {
+        let mut drained = 0.0;
+        for step in 0..60
+        {
+            if step == 30
+            {
+                world.brush(Tile::Empty(0.5), [45, 25], 4.0);
+            }
+            world.simulate(1.0 / 60.0);
+            let budget = &world.budgets()[0];
+            assert!(budget.residual().abs() < 1e-4, "step {}: {}", step, budget.to_csv());
+            // Nothing crosses the walled edges of the map.
+            assert_eq!(budget.flow, 0.0);
+            assert!(budget.drains.keys().all(|&drain| drain == [28, 20] || drain == [40, 10]));
+            drained += budget.drains.get(&[28, 20]).cloned().unwrap_or(0.0);
+        }
+        drained
+    }
+
+    #[test]
+    fn diffusion_is_accounted_for() -> ()
+    {
+        assert!(check_conservation(drained_world(Transport::Diffusion)) > 0.0);
+    }
+
+    #[test]
+    fn flow_is_accounted_for() -> ()
+    {
+        let world = drained_world(Transport::Fluid);
+        let diffused = check_conservation(drained_world(Transport::Diffusion));
+        let flowed = check_conservation(world);
+        assert!(flowed > diffused, "{} carried into the drain, {} diffused", flowed, diffused);
+    }
+
+    #[test]
+    fn flow_across_edges_is_accounted_for() -> ()
+    {
+        let mut world = World::with_boundaries(60, 40, [Boundary::Open, Boundary::Fixed(0.5)]);
+        world.brush(Tile::Empty(1.0), [50, 20], 8.0);
+        let mut carried = 0.0;
+        for _ in 0..60
+        {
+            world.simulate(1.0 / 60.0);
+            let budget = &world.budgets()[0];
+            assert!(budget.residual().abs() < 1e-4, "{}", budget.to_csv());
+            carried += budget.flow;
+        }
+        assert!(carried.abs() > 1.0);
+    }
+
+    #[test]
+    fn steps_follow_on_from_each_other() -> ()
+    {
+        let mut world = drained_world(Transport::Diffusion);
+        world.simulate(1.0 / 60.0);
+        let total = world.budgets()[0].total;
+        world.simulate(1.0 / 60.0);
+        assert_eq!(world.budgets()[0].initial, total);
+        assert!((world.budgets()[0].total - world.map().totals()[0]).abs() < 1e-9);
+    }
+}
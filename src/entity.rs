@@ -1,14 +1,30 @@
 use world;
+use fluid;
+use field;
+use spatial_hash::SpatialHash;
+
+pub const DEFAULT_RADIUS: f32 = 0.01;
+pub const DEFAULT_MASS: f32 = 1.0;
+/// How far from a wall, in tiles, an entity is placed after hitting it.
+const CONTACT_OFFSET: f32 = 0.001;
+/// Wall contacts resolved per step before the rest of the movement is dropped.
+const MAX_CONTACTS: u32 = 4;
 
-const DEACCELERATION: f32 = 0.01;
 pub type Coordinate = f32;
 pub type Point = [Coordinate; 2];
 pub type Vector = [f32; 2];
 
+#[derive(Serialize, Deserialize)]
 pub struct Entity
 {
+    /// Position in map space, in tiles.
     pub position: Point,
+    /// Velocity in metres per second.
     pub velocity: Vector,
+    /// Radius in metres.
+    pub radius: f32,
+    /// Mass in kilograms.
+    pub mass: f32,
 }
 
 impl Entity
@@ -17,75 +33,440 @@ impl Entity
     {
         Self
         {
-            position: position,
-            velocity: velocity,
+            position,
+            velocity,
+            radius: DEFAULT_RADIUS,
+            mass: DEFAULT_MASS,
         }
     }
 
-    pub fn simulate(&mut self, map: &world::Map) -> ()
+    pub fn simulate(&mut self, map: &world::Map, fluid: &fluid::Fluid,
+                    parameters: &world::Parameters, dt: f32) -> ()
     {
-        let from = self.map_position();
-        let get_value = |point: world::Point| match map.at(point)
-        {
-            Some(&world::Tile::Empty(value)) => value,
-            _ => 0.0,
-        };
-        let from_value = get_value(from);
-        for y in -1..2isize
+        let gradient = field::weighted_gradient(map, self.position, parameters.interpolation,
+                                                parameters.gradient_operator);
+        let force = [-gradient[0], -gradient[1]];
+        // The gradient is per tile, the response is per metre.
+        let acceleration = parameters.pressure_response / parameters.cell_size;
+        self.velocity[0] += force[0] * acceleration * dt;
+        self.velocity[1] += force[1] * acceleration * dt;
+        let flow = fluid.sample(self.position);
+        let drag = 1.0 - (-parameters.fluid_drag * dt).exp();
+        self.velocity[0] += (flow[0] - self.velocity[0]) * drag;
+        self.velocity[1] += (flow[1] - self.velocity[1]) * drag;
+        let drag = map.material_at(to_map_position(self.position)).map_or(0.0, |material| material.drag);
+        let damping = (-(parameters.damping + drag) * dt).exp();
+        self.velocity = [self.velocity[0] * damping,
+                         self.velocity[1] * damping];
+        // After every wall contact the rest of the step continues with the new velocity.
+        let mut remaining = dt / parameters.cell_size;
+        for _ in 0..MAX_CONTACTS
         {
-            for x in -1..2isize
+            let from = self.position;
+            let to = [from[0] + self.velocity[0] * remaining, from[1] + self.velocity[1] * remaining];
+            match find_contact(map, from, to)
             {
-                if x != 0 && y != 0
+                Some(contact) =>
                 {
-                    let direction = [x as world::Coordinate, y as world::Coordinate];
-                    let to = [from[0] + direction[0], from[1] + direction[1]];
-                    let to_value = get_value(to);
-                    let distance = (((from[0] - to[0]) as f32).powi(2) +
-                                     ((from[1] - to[1]) as f32).powi(2)).sqrt();
-                    let delta = (to_value - from_value) / distance; //No operation if not empty?
-                    self.velocity[0] += (-direction[0].signum() as f32) * delta;
-                    self.velocity[1] += (-direction[1].signum() as f32) * delta;
-                }
+                    self.hit_wall(from, to, &contact, parameters);
+                    remaining *= 1.0 - contact.time;
+                },
+                None =>
+                {
+                    self.position = to;
+                    break;
+                },
             }
         }
-        self.velocity = [self.velocity[0] * (1.0 - DEACCELERATION),
-                         self.velocity[1] * (1.0 - DEACCELERATION)];
-        let from = self.position;
-        let to = [from[0] + self.velocity[0], from[1] + self.velocity[1]];
-        let to_map = [(to[0] + 0.5) as world::Coordinate, (to[1] + 0.5) as world::Coordinate];
-        match map.at(to_map)
+        self.position = wrap(map, self.position);
+    }
+
+    /// Stops the entity at the contact point and reflects the normal component of its
+    /// velocity, scaled by the wall restitution. Coulomb friction takes tangential speed
+    /// proportional to the normal impulse.
+    fn hit_wall(&mut self, from: Point, to: Point, contact: &Contact, parameters: &world::Parameters) -> ()
+    {
+        let normal = contact.normal;
+        self.position = [from[0] + (to[0] - from[0]) * contact.time + normal[0] * CONTACT_OFFSET,
+                         from[1] + (to[1] - from[1]) * contact.time + normal[1] * CONTACT_OFFSET];
+        let normal_speed = self.velocity[0] * normal[0] + self.velocity[1] * normal[1];
+        if normal_speed >= 0.0
         {
-            Some(&world::Tile::Empty(_)) => self.position = to,
-            _ => self.bounce(),
+            return;
         }
+        let tangent = [self.velocity[0] - normal_speed * normal[0],
+                       self.velocity[1] - normal_speed * normal[1]];
+        let tangent_speed = (tangent[0].powi(2) + tangent[1].powi(2)).sqrt();
+        let impulse = -(1.0 + parameters.wall_restitution) * normal_speed;
+        let friction = if tangent_speed > 0.0
+        {
+            (1.0 - parameters.wall_friction * impulse / tangent_speed).max(0.0)
+        }
+        else
+        {
+            0.0
+        };
+        self.velocity = [tangent[0] * friction - parameters.wall_restitution * normal_speed * normal[0],
+                         tangent[1] * friction - parameters.wall_restitution * normal_speed * normal[1]];
     }
+}
+
+struct Contact
+{
+    /// Fraction of the movement done before the contact.
+    time: f32,
+    /// Unit normal of the tile face that was hit, pointing out of the wall.
+    normal: Vector,
+}
 
-    fn map_position(&self) -> world::Point
+/// Finds where the movement from `from` to `to` first enters a tile that isn't empty by
+/// walking every tile along the way (Amanatides-Woo traversal), so no wall can be skipped
+/// regardless of speed. Entities that start out inside a wall are let out freely. Edges of
+/// the map count as walls unless its boundaries let entities through.
+fn find_contact(map: &world::Map, from: Point, to: Point) -> Option<Contact>
+{
+    let is_open = |point: world::Point| matches!(map.resolve(point), Some(world::Tile::Empty(_)));
+    let start = to_map_position(from);
+    if !is_open(start)
     {
-        [(self.position[0] + 0.5) as world::Coordinate,
-         (self.position[1] + 0.5) as world::Coordinate]
+        return None;
     }
-
-    fn bounce(&mut self) -> ()
+    let end = to_map_position(to);
+    let delta = [to[0] - from[0], to[1] - from[1]];
+    let mut step = [0; 2];
+    let mut next_crossing = [f32::INFINITY; 2];
+    let mut crossing_interval = [f32::INFINITY; 2];
+    for axis in 0..2
     {
-        self.velocity = [self.velocity[0] * -1.0, self.velocity[1] * -1.0];
+        if delta[axis] > 0.0
+        {
+            step[axis] = 1;
+            next_crossing[axis] = ((start[axis] + 1) as f32 - from[axis]) / delta[axis];
+            crossing_interval[axis] = 1.0 / delta[axis];
+        }
+        else if delta[axis] < 0.0
+        {
+            step[axis] = -1;
+            next_crossing[axis] = (start[axis] as f32 - from[axis]) / delta[axis];
+            crossing_interval[axis] = -1.0 / delta[axis];
+        }
     }
+    let mut cell = start;
+    while cell != end
+    {
+        let axis = if next_crossing[0] < next_crossing[1] { 0 } else { 1 };
+        let time = next_crossing[axis];
+        if time > 1.0
+        {
+            break;
+        }
+        cell[axis] += step[axis];
+        if !is_open(cell)
+        {
+            let mut normal = [0.0, 0.0];
+            normal[axis] = -step[axis] as f32;
+            return Some(Contact{time, normal});
+        }
+        next_crossing[axis] += crossing_interval[axis];
+    }
+    None
 }
 
+/// Moves `position` back into the map along its periodic axes.
+fn wrap(map: &world::Map, position: Point) -> Point
+{
+    let tile = to_map_position(position);
+    let wrapped = map.wrap(tile);
+    [position[0] + (wrapped[0] - tile[0]) as Coordinate,
+     position[1] + (wrapped[1] - tile[1]) as Coordinate]
+}
+
+/// The tile containing `position`, tile `[x, y]` covering `[x, x + 1) x [y, y + 1)`.
+fn to_map_position(position: Point) -> world::Point
+{
+    [position[0].floor() as world::Coordinate,
+     position[1].floor() as world::Coordinate]
+}
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct EntityContainer(pub Vec<Entity>);
 
 impl EntityContainer
 {
     pub fn new() -> Self
     {
-        Self{0: Vec::new()}
+        Self(Vec::new())
     }
 
-    pub fn simulate(&mut self, map: &world::Map) -> ()
+    pub fn simulate(&mut self, map: &world::Map, fluid: &fluid::Fluid,
+                    parameters: &world::Parameters, dt: f32) -> ()
+    {
+        self.integrate(map, fluid, parameters, dt);
+        self.collide(map, parameters);
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn integrate(&mut self, map: &world::Map, fluid: &fluid::Fluid,
+                 parameters: &world::Parameters, dt: f32) -> ()
     {
         for i in self.0.iter_mut()
         {
-            i.simulate(map);
+            i.simulate(map, fluid, parameters, dt);
+        }
+    }
+
+    /// Entities move independently of each other, so they can be integrated in any order.
+    #[cfg(feature = "parallel")]
+    fn integrate(&mut self, map: &world::Map, fluid: &fluid::Fluid,
+                 parameters: &world::Parameters, dt: f32) -> ()
+    {
+        use rayon::prelude::*;
+
+        self.0.par_iter_mut().for_each(|i| i.simulate(map, fluid, parameters, dt));
+    }
+
+    /// Lets every entity displace the value field around it: value proportional to its
+    /// momentum is moved from behind it to in front of it, so moving entities push the field
+    /// along without changing its total.
+    pub fn disturb(&self, map: &mut world::Map, parameters: &world::Parameters, dt: f32) -> ()
+    {
+        if parameters.entity_coupling == 0.0
+        {
+            return;
+        }
+        for entity in self.0.iter()
+        {
+            let speed = (entity.velocity[0].powi(2) + entity.velocity[1].powi(2)).sqrt();
+            if speed == 0.0
+            {
+                continue;
+            }
+            let amount = parameters.entity_coupling * entity.mass * speed * dt;
+            let offset = entity.radius / (parameters.cell_size * speed);
+            let front = [entity.position[0] + entity.velocity[0] * offset,
+                         entity.position[1] + entity.velocity[1] * offset];
+            let back = [entity.position[0] - entity.velocity[0] * offset,
+                        entity.position[1] - entity.velocity[1] * offset];
+            // Nothing is moved unless both sides can take it, so none is made or lost at walls.
+            if field::can_deposit(map, front) && field::can_deposit(map, back)
+            {
+                field::deposit(map, front, amount);
+                field::deposit(map, back, -amount);
+            }
+        }
+    }
+
+    /// Pushes overlapping entities apart and exchanges momentum between those approaching
+    /// each other, losing energy according to `parameters.entity_restitution`. Every overlap
+    /// is worked out from the positions and velocities before the collision phase and applied
+    /// to both entities with opposite signs, so momentum is conserved and the result doesn't
+    /// depend on the order in which entities are visited.
+    fn collide(&mut self, map: &world::Map, parameters: &world::Parameters) -> ()
+    {
+        let mut max_radius = 0.0f32;
+        for entity in self.0.iter()
+        {
+            max_radius = max_radius.max(entity.radius);
+        }
+        if self.0.len() < 2 || max_radius <= 0.0
+        {
+            return;
         }
+        let mut hash = SpatialHash::new(2.0 * max_radius / parameters.cell_size);
+        for (i, entity) in self.0.iter().enumerate()
+        {
+            hash.insert(i, entity.position);
+        }
+        let mut responses: Vec<Response> = self.0.iter()
+            .map(|_| Response{displacement: [0.0, 0.0], velocity: [0.0, 0.0]}).collect();
+        for overlap in self.overlaps(&hash, parameters).into_iter().flat_map(|overlaps| overlaps.into_iter())
+        {
+            for &(index, sign, inverse_mass) in [(overlap.a, -1.0, overlap.inverse_a),
+                                                 (overlap.b, 1.0, overlap.inverse_b)].iter()
+            {
+                let response = &mut responses[index];
+                let separation = sign * overlap.separation * inverse_mass;
+                response.displacement[0] += overlap.normal[0] * separation;
+                response.displacement[1] += overlap.normal[1] * separation;
+                let impulse = sign * overlap.impulse * inverse_mass;
+                response.velocity[0] += overlap.normal[0] * impulse;
+                response.velocity[1] += overlap.normal[1] * impulse;
+            }
+        }
+        for (entity, response) in self.0.iter_mut().zip(responses)
+        {
+            let to = [entity.position[0] + response.displacement[0],
+                      entity.position[1] + response.displacement[1]];
+            if let Some(world::Tile::Empty(_)) = map.at(to_map_position(to))
+            {
+                entity.position = to;
+            }
+            entity.velocity[0] += response.velocity[0];
+            entity.velocity[1] += response.velocity[1];
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn overlaps(&self, hash: &SpatialHash, parameters: &world::Parameters) -> Vec<Vec<Overlap>>
+    {
+        (0..self.0.len()).map(|i| self.overlaps_of(i, hash, parameters)).collect()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn overlaps(&self, hash: &SpatialHash, parameters: &world::Parameters) -> Vec<Vec<Overlap>>
+    {
+        use rayon::prelude::*;
+
+        (0..self.0.len()).into_par_iter().map(|i| self.overlaps_of(i, hash, parameters)).collect()
+    }
+
+    /// Overlaps of entity `i` with the entities that come after it, so that every pair is
+    /// only resolved once.
+    fn overlaps_of(&self, i: usize, hash: &SpatialHash, parameters: &world::Parameters) -> Vec<Overlap>
+    {
+        let a = &self.0[i];
+        let inverse_a = if a.mass > 0.0 { 1.0 / a.mass } else { 0.0 };
+        let mut overlaps = Vec::new();
+        for j in hash.neighbours(a.position)
+        {
+            if j <= i
+            {
+                continue;
+            }
+            let b = &self.0[j];
+            // Positions are in tiles, everything else in metres.
+            let delta = [(b.position[0] - a.position[0]) * parameters.cell_size,
+                         (b.position[1] - a.position[1]) * parameters.cell_size];
+            let distance = (delta[0].powi(2) + delta[1].powi(2)).sqrt();
+            let overlap = a.radius + b.radius - distance;
+            if overlap <= 0.0
+            {
+                continue;
+            }
+            // Coincident entities are split along x.
+            let normal = if distance > 0.0 { [delta[0] / distance, delta[1] / distance] } else { [1.0, 0.0] };
+            let inverse_b = if b.mass > 0.0 { 1.0 / b.mass } else { 0.0 };
+            let inverse_total = inverse_a + inverse_b;
+            if inverse_total <= 0.0
+            {
+                continue;
+            }
+            let approach = (b.velocity[0] - a.velocity[0]) * normal[0] +
+                           (b.velocity[1] - a.velocity[1]) * normal[1];
+            overlaps.push(Overlap
+            {
+                a: i,
+                b: j,
+                normal,
+                inverse_a,
+                inverse_b,
+                separation: overlap / (inverse_total * parameters.cell_size),
+                impulse: if approach < 0.0
+                {
+                    -(1.0 + parameters.entity_restitution) * approach / inverse_total
+                }
+                else
+                {
+                    0.0
+                },
+            });
+        }
+        overlaps
+    }
+}
+
+/// Overlap of entities `a` and `b`, pushing `a` away from `b` along `normal` and `b` the
+/// other way, each in proportion to its inverse mass.
+struct Overlap
+{
+    a: usize,
+    b: usize,
+    normal: Vector,
+    inverse_a: f32,
+    inverse_b: f32,
+    /// Distance the entities are moved apart, in tiles, times their reduced mass.
+    separation: f32,
+    /// Magnitude of the impulse exchanged, in kilogram metres per second.
+    impulse: f32,
+}
+
+/// Change of an entity's position, in tiles, and velocity from its collisions in one step.
+struct Response
+{
+    displacement: Vector,
+    velocity: Vector,
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn momentum(entities: &EntityContainer) -> Vector
+    {
+        entities.0.iter().fold([0.0, 0.0], |momentum, entity|
+            [momentum[0] + entity.mass * entity.velocity[0], momentum[1] + entity.mass * entity.velocity[1]])
+    }
+
+    #[test]
+    fn collisions_conserve_momentum() -> ()
+    {
+        let map = world::Map::new(20, 20);
+        let parameters = world::Parameters::default();
+        // The middle entity touches both others, which only touch it.
+        let mut entities = EntityContainer::new();
+        entities.0.push(Entity::new([5.0, 5.5], [1.0, 0.0]));
+        entities.0.push(Entity{mass: 2.0, ..Entity::new([6.5, 5.5], [-1.0, 0.0])});
+        entities.0.push(Entity::new([6.5, 7.0], [0.0, -1.0]));
+        let before = momentum(&entities);
+        entities.collide(&map, &parameters);
+        let after = momentum(&entities);
+        assert!(entities.0[0].velocity[0] < 0.0 && entities.0[2].velocity[1] > 0.0);
+        assert!((after[0] - before[0]).abs() < 1e-6 && (after[1] - before[1]).abs() < 1e-6,
+                "momentum changed from {:?} to {:?}", before, after);
+    }
+
+    #[test]
+    fn fast_entities_stop_at_thin_walls() -> ()
+    {
+        let mut map = world::Map::new(40, 20);
+        for y in 1..19
+        {
+            *map.at_mut([20, y]).unwrap() = world::Tile::Wall;
+        }
+        let fluid = fluid::Fluid::new(&map);
+        let parameters = world::Parameters::default();
+        // At 1000 m/s an entity crosses the whole map many times over in a single step.
+        let mut entities = EntityContainer::new();
+        entities.0.push(Entity::new([10.5, 10.5], [1000.0, 0.0]));
+        entities.0.push(Entity::new([10.5, 10.5], [1000.0, 370.0]));
+        for _ in 0..10
+        {
+            entities.simulate(&map, &fluid, &parameters, 1.0 / 60.0);
+            for entity in entities.0.iter()
+            {
+                assert!(entity.position[0] > 1.0 && entity.position[0] < 20.0 &&
+                        entity.position[1] > 1.0 && entity.position[1] < 19.0,
+                        "entity got through to {:?}", entity.position);
+            }
+        }
+    }
+
+    #[test]
+    fn grazing_hits_only_reflect_the_normal_component() -> ()
+    {
+        let map = world::Map::new(40, 20);
+        let fluid = fluid::Fluid::new(&map);
+        let parameters = world::Parameters{damping: 0.0, fluid_drag: 0.0, ..world::Parameters::default()};
+        // Sliding along the walled top edge while sinking into it by a tenth of the speed.
+        let dt = 1.0 / 60.0;
+        let mut entity = Entity::new([5.5, 1.1], [3.0, -0.3]);
+        entity.simulate(&map, &fluid, &parameters, dt);
+        let impulse = (1.0 + parameters.wall_restitution) * 0.3;
+        let tangent = 3.0 - parameters.wall_friction * impulse;
+        let normal = parameters.wall_restitution * 0.3;
+        assert!((entity.velocity[0] - tangent).abs() < 1e-4 && (entity.velocity[1] - normal).abs() < 1e-4,
+                "velocity {:?} instead of {:?}", entity.velocity, [tangent, normal]);
+        assert!(entity.position[0] > 5.5 && entity.position[1] >= 1.0);
     }
 }
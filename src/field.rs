@@ -0,0 +1,386 @@
+//! Interpolation of the fields of a map at arbitrary points in map space.
+//!
+//! Values are taken to live at tile centres, so tile `[x, y]` holds the value at
+//! `[x + 0.5, y + 0.5]`. `Tile::Drain` counts as a value of zero and `Tile::Source` as its
+//! own value. Points past the edge of the map take whatever the boundaries of the map and of
+//! the field make of them.
+//! Walls, emitters, sinks and points past walled edges have no value; within a stencil they
+//! take the mean of the nearest nodes that do, so the field is flat across a wall face and has
+//! no gradient pointing into it.
+
+use std;
+
+use world;
+use entity;
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Interpolation
+{
+    Bilinear,
+    /// Catmull-Rom bicubic interpolation over a 4x4 stencil.
+    Bicubic,
+}
+
+/// How the gradient of the field is estimated.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum GradientOperator
+{
+    /// Exact derivative of the interpolated field, which is discontinuous across tile
+    /// centres for bilinear interpolation.
+    Interpolated,
+    /// `(right - left) / 2` at every tile centre.
+    CentralDifference,
+    /// Least-squares fit over all 8 neighbours (the Prewitt kernel).
+    EightNeighbour,
+    Sobel,
+    Scharr,
+}
+
+impl GradientOperator
+{
+    /// Kernel of the x derivative, indexed `[y][x]`; the y kernel is its transpose. Every
+    /// kernel is normalized to be exact for linear fields.
+    fn kernel(&self) -> [[f32; 3]; 3]
+    {
+        match self
+        {
+            &GradientOperator::Interpolated | &GradientOperator::CentralDifference =>
+                [[0.0, 0.0, 0.0], [-0.5, 0.0, 0.5], [0.0, 0.0, 0.0]],
+            &GradientOperator::EightNeighbour =>
+                [[-1.0 / 6.0, 0.0, 1.0 / 6.0], [-1.0 / 6.0, 0.0, 1.0 / 6.0], [-1.0 / 6.0, 0.0, 1.0 / 6.0]],
+            &GradientOperator::Sobel =>
+                [[-1.0 / 8.0, 0.0, 1.0 / 8.0], [-2.0 / 8.0, 0.0, 2.0 / 8.0], [-1.0 / 8.0, 0.0, 1.0 / 8.0]],
+            &GradientOperator::Scharr =>
+                [[-3.0 / 32.0, 0.0, 3.0 / 32.0], [-10.0 / 32.0, 0.0, 10.0 / 32.0], [-3.0 / 32.0, 0.0, 3.0 / 32.0]],
+        }
+    }
+}
+
+impl std::str::FromStr for GradientOperator
+{
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err>
+    {
+        match name
+        {
+            "interpolated" => Ok(GradientOperator::Interpolated),
+            "central" => Ok(GradientOperator::CentralDifference),
+            "eight-neighbour" => Ok(GradientOperator::EightNeighbour),
+            "sobel" => Ok(GradientOperator::Sobel),
+            "scharr" => Ok(GradientOperator::Scharr),
+            _ => Err(format!("unknown gradient operator {}", name)),
+        }
+    }
+}
+
+impl std::str::FromStr for Interpolation
+{
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err>
+    {
+        match name
+        {
+            "bilinear" => Ok(Interpolation::Bilinear),
+            "bicubic" => Ok(Interpolation::Bicubic),
+            _ => Err(format!("unknown interpolation {}", name)),
+        }
+    }
+}
+
+pub fn sample(map: &world::Map, field: world::FieldIndex, point: entity::Point, interpolation: Interpolation)
+    -> f32
+{
+    sample_with_gradient(map, field, point, interpolation).0
+}
+
+/// Gradient of `field` at `point`, in value per tile. Apart from `Interpolated`, the
+/// operator is applied at the surrounding tile centres and the results are interpolated.
+pub fn gradient(map: &world::Map, field: world::FieldIndex, point: entity::Point, interpolation: Interpolation,
+                operator: GradientOperator) -> [f32; 2]
+{
+    if operator == GradientOperator::Interpolated
+    {
+        return sample_with_gradient(map, field, point, interpolation).1;
+    }
+    let (origin, size, x_weights, y_weights) = locate(point, interpolation);
+    let block_size = size + 2;
+    let values = stencil(map, field, [origin[0] - 1, origin[1] - 1], block_size);
+    let kernel = operator.kernel();
+    let mut gradient = [0.0, 0.0];
+    for j in 0..size
+    {
+        for i in 0..size
+        {
+            let weight = x_weights.0[i] * y_weights.0[j];
+            for ky in 0..3
+            {
+                for kx in 0..3
+                {
+                    let node = values[((j + ky) * block_size) + i + kx];
+                    gradient[0] += weight * kernel[ky][kx] * node;
+                    gradient[1] += weight * kernel[kx][ky] * node;
+                }
+            }
+        }
+    }
+    gradient
+}
+
+/// Sum of the gradients of the fields at `point`, weighted by their force weights.
+pub fn weighted_gradient(map: &world::Map, point: entity::Point, interpolation: Interpolation,
+                         operator: GradientOperator) -> [f32; 2]
+{
+    let mut sum = [0.0, 0.0];
+    for (index, field) in map.fields().iter().enumerate().filter(|&(_, field)| field.force_weight != 0.0)
+    {
+        let gradient = gradient(map, index, point, interpolation, operator);
+        sum[0] += field.force_weight * gradient[0];
+        sum[1] += field.force_weight * gradient[1];
+    }
+    sum
+}
+
+pub fn sample_with_gradient(map: &world::Map, field: world::FieldIndex, point: entity::Point,
+                            interpolation: Interpolation) -> (f32, [f32; 2])
+{
+    let (origin, size, x_weights, y_weights) = locate(point, interpolation);
+    let values = stencil(map, field, origin, size);
+    combine(&values, size, &x_weights, &y_weights)
+}
+
+/// The first tile of the interpolation stencil around `point`, its size and its weights.
+fn locate(point: entity::Point, interpolation: Interpolation) -> (world::Point, usize, Weights, Weights)
+{
+    let x = point[0] - 0.5;
+    let y = point[1] - 0.5;
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as world::Coordinate, y0 as world::Coordinate);
+    match interpolation
+    {
+        Interpolation::Bilinear => ([x0, y0], 2, linear_weights(fx), linear_weights(fy)),
+        Interpolation::Bicubic => ([x0 - 1, y0 - 1], 4, cubic_weights(fx), cubic_weights(fy)),
+    }
+}
+
+/// Weights of a 1D stencil and their derivatives.
+type Weights = ([f32; 4], [f32; 4]);
+
+fn linear_weights(t: f32) -> Weights
+{
+    ([1.0 - t, t, 0.0, 0.0], [-1.0, 1.0, 0.0, 0.0])
+}
+
+fn cubic_weights(t: f32) -> Weights
+{
+    let t2 = t * t;
+    let t3 = t2 * t;
+    ([(-t3 + 2.0 * t2 - t) / 2.0,
+      (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+      (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+      (t3 - t2) / 2.0],
+     [(-3.0 * t2 + 4.0 * t - 1.0) / 2.0,
+      (9.0 * t2 - 10.0 * t) / 2.0,
+      (-9.0 * t2 + 8.0 * t + 1.0) / 2.0,
+      (3.0 * t2 - 2.0 * t) / 2.0])
+}
+
+fn combine(values: &[f32], size: usize, x_weights: &Weights, y_weights: &Weights) -> (f32, [f32; 2])
+{
+    let mut value = 0.0;
+    let mut gradient = [0.0, 0.0];
+    for j in 0..size
+    {
+        for i in 0..size
+        {
+            let node = values[(j * size) + i];
+            value += x_weights.0[i] * y_weights.0[j] * node;
+            gradient[0] += x_weights.1[i] * y_weights.0[j] * node;
+            gradient[1] += x_weights.0[i] * y_weights.1[j] * node;
+        }
+    }
+    (value, gradient)
+}
+
+const MAX_STENCIL: usize = 6;
+
+/// Reads the `size` x `size` block of values of `field` starting at `origin`, filling in walls.
+fn stencil(map: &world::Map, field: world::FieldIndex, origin: world::Point, size: usize)
+    -> [f32; MAX_STENCIL * MAX_STENCIL]
+{
+    let mut values = [0.0; MAX_STENCIL * MAX_STENCIL];
+    let mut known = [false; MAX_STENCIL * MAX_STENCIL];
+    for j in 0..size
+    {
+        for i in 0..size
+        {
+            let index = (j * size) + i;
+            let point = [origin[0] + i as world::Coordinate, origin[1] + j as world::Coordinate];
+            let value = match map.resolve_field(field, point)
+            {
+                Some(world::Tile::Empty(value)) => Some(value),
+                Some(world::Tile::Drain) => Some(0.0),
+                Some(world::Tile::Source(value)) => Some(value),
+                _ => None,
+            };
+            if let Some(value) = value
+            {
+                values[index] = value;
+                known[index] = true;
+            }
+        }
+    }
+    if known[..(size * size)].iter().all(|&known| known)
+    {
+        return values;
+    }
+    let mut filled = values;
+    for index in 0..(size * size)
+    {
+        if known[index]
+        {
+            continue;
+        }
+        let mut nearest = usize::MAX;
+        let mut sum = 0.0;
+        let mut count = 0u32;
+        for other in 0..(size * size)
+        {
+            if !known[other]
+            {
+                continue;
+            }
+            let dx = (index % size) as isize - (other % size) as isize;
+            let dy = (index / size) as isize - (other / size) as isize;
+            let distance = (dx * dx + dy * dy) as usize;
+            if distance < nearest
+            {
+                nearest = distance;
+                sum = 0.0;
+                count = 0;
+            }
+            if distance == nearest
+            {
+                sum += values[other];
+                count += 1;
+            }
+        }
+        filled[index] = if count > 0 { sum / count as f32 } else { 0.0 };
+    }
+    filled
+}
+
+/// Whether `deposit` finds any `Tile::Empty` around `point` to add to.
+pub fn can_deposit(map: &world::Map, point: entity::Point) -> bool
+{
+    !deposit_targets(map, point).is_empty()
+}
+
+/// Adds `amount` of pressure to the tiles around `point`, split with bilinear weights among the ones
+/// that are `Tile::Empty`, wrapping around periodic edges. Returns the amount that was
+/// actually deposited, which is zero when none of them are.
+pub fn deposit(map: &mut world::Map, point: entity::Point, amount: f32) -> f32
+{
+    let targets = deposit_targets(map, point);
+    if targets.is_empty()
+    {
+        return 0.0;
+    }
+    for &(position, weight) in targets.iter()
+    {
+        if let Some(mut tile) = map.at_mut(position)
+        {
+            if let world::Tile::Empty(ref mut value) = *tile
+            {
+                *value += amount * weight;
+            }
+        }
+    }
+    amount
+}
+
+/// The empty tiles around `point` with their share of a deposit.
+fn deposit_targets(map: &world::Map, point: entity::Point) -> Vec<(world::Point, f32)>
+{
+    let (origin, _, x_weights, y_weights) = locate(point, Interpolation::Bilinear);
+    let mut targets = Vec::with_capacity(4);
+    let mut total_weight = 0.0;
+    for j in 0..2
+    {
+        for i in 0..2
+        {
+            let position = map.wrap([origin[0] + i as world::Coordinate, origin[1] + j as world::Coordinate]);
+            let weight = x_weights.0[i] * y_weights.0[j];
+            if let (Some(world::Tile::Empty(_)), true) = (map.at(position), weight > 0.0)
+            {
+                targets.push((position, weight));
+                total_weight += weight;
+            }
+        }
+    }
+    for target in targets.iter_mut()
+    {
+        target.1 /= total_weight;
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use fluid;
+
+    /// A map holding `1 + slope[0] * x + slope[1] * y` at the centre of every tile within its walls.
+    fn linear(slope: [f32; 2]) -> world::Map
+    {
+        let mut map = world::Map::new(40, 30);
+        let bounds = world::Bounds::new([1, 1], 38, 28);
+        let tiles: Vec<world::Tile> = (0..(38 * 28)).map(|i|
+        {
+            let point = bounds.point(i);
+            world::Tile::Empty(slope[0] * (point[0] as f32 + 0.5) + slope[1] * (point[1] as f32 + 0.5) + 1.0)
+        }).collect();
+        map.write(bounds, &tiles);
+        map
+    }
+
+    #[test]
+    fn gradients_are_exact_for_linear_fields() -> ()
+    {
+        let slope = [0.03, -0.02];
+        let map = linear(slope);
+        let operators = [GradientOperator::Interpolated, GradientOperator::CentralDifference,
+                         GradientOperator::EightNeighbour, GradientOperator::Sobel, GradientOperator::Scharr];
+        for &interpolation in [Interpolation::Bilinear, Interpolation::Bicubic].iter()
+        {
+            for &operator in operators.iter()
+            {
+                for &point in [[20.0, 15.0], [12.3, 7.8], [27.5, 21.25]].iter()
+                {
+                    let gradient = gradient(&map, 0, point, interpolation, operator);
+                    assert!((gradient[0] - slope[0]).abs() < 1e-5 && (gradient[1] - slope[1]).abs() < 1e-5,
+                            "{:?} {:?} gradient {:?} at {:?}", interpolation, operator, gradient, point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn entities_are_pushed_down_the_gradient() -> ()
+    {
+        let slope = [0.03, -0.02];
+        let map = linear(slope);
+        let fluid = fluid::Fluid::new(&map);
+        let parameters = world::Parameters{damping: 0.0, fluid_drag: 0.0, ..world::Parameters::default()};
+        let mut entity = entity::Entity::new([20.0, 15.0], [0.0, 0.0]);
+        entity.simulate(&map, &fluid, &parameters, 1.0 / 60.0);
+        let velocity = entity.velocity;
+        let alignment = -(velocity[0] * slope[0] + velocity[1] * slope[1]) /
+            ((velocity[0].powi(2) + velocity[1].powi(2)).sqrt() * (slope[0].powi(2) + slope[1].powi(2)).sqrt());
+        assert!(alignment > 0.9999, "velocity {:?} against a slope of {:?}", velocity, slope);
+    }
+}
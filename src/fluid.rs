@@ -0,0 +1,711 @@
+//! Stable-fluids style velocity solver living on the map grid.
+//!
+//! Every step the velocity is diffused by the viscosity, slowed down by the drag of the
+//! materials it passes through, made incompressible by a pressure projection, advected along
+//! itself and projected again, after which the values of every field of the map are advected
+//! by the resulting flow, before the map diffuses them. Pressure values act as volume sources
+//! during the projection, so positive regions push fluid outwards and negative regions draw it
+//! in. Values are advected by moving them between neighbouring tiles, so whatever leaves one
+//! tile enters another or is tallied where it leaves the map.
+//! `Tile::Drain` is an outflow boundary held at zero pressure, and every other kind of tile
+//! is a solid boundary, as are empty tiles of impermeable materials. Partly permeable ones
+//! slow the flow down like a porous medium and let through that much of what it carries.
+//! Past the edges of the map the flow wraps around periodic boundaries, treats walled ones
+//! as solid and leaves through open and fixed-value ones as through drains.
+//!
+//! The pressure projection couples every cell to every other one, so unlike the diffusion of
+//! the map the flow is solved over all of it every step, however little of it is active. Only
+//! the islands of loaded chunks of an unbounded map are solved apart, as no flow crosses the
+//! blank chunks between them.
+
+use std;
+
+use world;
+use entity;
+use budget;
+
+/// Velocity in metres per second.
+pub type Velocity = [f32; 2];
+
+/// Rate at which a material of permeability `0.5` slows the flow down, per second, on top of
+/// its drag. It grows without bound as the permeability goes to zero.
+const POROUS_DRAG: f32 = 10.0;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Parameters
+{
+    /// Kinematic viscosity, in square metres per second.
+    pub viscosity: f32,
+    /// Rate at which a tile of value `1.0` injects volume, per second.
+    pub source_rate: f32,
+    /// Rate at which pressure values decay towards zero as they are advected, per second.
+    pub decay_rate: f32,
+    /// Red-black Gauss-Seidel iterations used by the viscosity and pressure solves.
+    pub iterations: u32,
+}
+
+impl Default for Parameters
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            viscosity: 0.00006,
+            source_rate: 3.0,
+            decay_rate: 0.0,
+            iterations: 20,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Cell
+{
+    Fluid,
+    Solid,
+    Outflow,
+}
+
+/// The flow covers every island of loaded chunks of the map with a region of its own, which
+/// are refitted as the map grows or shrinks.
+#[derive(Serialize, Deserialize)]
+pub struct Fluid
+{
+    regions: Vec<Region>,
+    pub parameters: Parameters,
+}
+
+/// Flow over a rectangle of the map, past which lie the boundaries of the map or, for an
+/// island of an unbounded map, blank chunks the flow treats as walls.
+#[derive(Serialize, Deserialize)]
+struct Region
+{
+    bounds: world::Bounds,
+    /// Boundaries of the map along x and y.
+    boundaries: [world::Boundary; 2],
+    velocity: Vec<Velocity>,
+}
+
+impl Fluid
+{
+    /// A flow at rest covering `map`.
+    pub fn new(map: &world::Map) -> Self
+    {
+        let mut fluid = Self
+        {
+            regions: Vec::new(),
+            parameters: Parameters::default(),
+        };
+        fluid.fit(map);
+        fluid
+    }
+
+    /// Bounds of the regions the flow is solved over separately.
+    pub fn regions(&self) -> Vec<world::Bounds>
+    {
+        self.regions.iter().map(|region| region.bounds).collect()
+    }
+
+    pub fn is_consistent(&self) -> bool
+    {
+        self.regions.iter().all(|region| region.velocity.len() == region.bounds.len())
+    }
+
+    /// Moves the flow onto the islands of `map`, keeping the velocity where the old and new
+    /// regions overlap.
+    pub fn fit(&mut self, map: &world::Map) -> ()
+    {
+        let islands = map.islands();
+        let boundaries = map.boundaries();
+        if islands == self.regions() && self.regions.iter().all(|region| region.boundaries == boundaries)
+        {
+            return;
+        }
+        let regions = islands.into_iter().map(|bounds| Region
+        {
+            bounds,
+            boundaries,
+            velocity: (0..bounds.len()).map(|i| self.velocity_at(bounds.point(i)).unwrap_or([0.0, 0.0])).collect(),
+        }).collect();
+        self.regions = regions;
+    }
+
+    pub fn velocity_at(&self, point: world::Point) -> Option<Velocity>
+    {
+        self.regions.iter().filter_map(|region| region.to_index(point).map(|index| region.velocity[index])).next()
+    }
+
+    /// Bilinearly interpolated velocity at a point in map space, where tile `[x, y]` covers
+    /// `[x, x + 1) x [y, y + 1)`. The flow is still past the edges of its regions.
+    pub fn sample(&self, point: entity::Point) -> Velocity
+    {
+        let tile = [point[0].floor() as world::Coordinate, point[1].floor() as world::Coordinate];
+        match self.regions.iter().find(|region| region.to_index(tile).is_some())
+        {
+            Some(region) => region.sample(point),
+            None => [0.0, 0.0],
+        }
+    }
+
+    /// Advances the flow by `dt` seconds on a grid of `cell_size` metre tiles. Returns what
+    /// it carried into every drain and across the edges of the map, for every field.
+    pub fn simulate(&mut self, map: &mut world::Map, cell_size: f32, dt: f32) -> Vec<budget::Budget>
+    {
+        assert!(map.islands() == self.regions(), "Fluid regions and map islands differ.");
+        let mut budgets = vec![budget::Budget::default(); map.fields().len()];
+        for region in self.regions.iter_mut()
+        {
+            let carried = region.simulate(map, &self.parameters, cell_size, dt);
+            for (budget, carried) in budgets.iter_mut().zip(carried.iter())
+            {
+                budget.add(carried);
+            }
+        }
+        budgets
+    }
+}
+
+impl Region
+{
+    /// Bilinearly interpolated velocity at a point in map space.
+    fn sample(&self, point: entity::Point) -> Velocity
+    {
+        let mut result = [0.0, 0.0];
+        for &(index, weight) in self.stencil(point).iter()
+        {
+            if let Some(index) = index
+            {
+                result[0] += self.velocity[index][0] * weight;
+                result[1] += self.velocity[index][1] * weight;
+            }
+        }
+        result
+    }
+
+    fn simulate(&mut self, map: &mut world::Map, parameters: &Parameters, cell_size: f32, dt: f32)
+        -> Vec<budget::Budget>
+    {
+        let tiles = map.tiles(self.bounds);
+        let materials = map.material_indices(self.bounds);
+        let cells = self.classify(&tiles, &materials, map.materials());
+        let permeability: Vec<f32> = materials.iter()
+            .map(|&material| map.materials()[material as usize].permeability).collect();
+        let sources = self.sources(&tiles, parameters.source_rate);
+        for (velocity, &cell) in self.velocity.iter_mut().zip(cells.iter())
+        {
+            if cell != Cell::Fluid
+            {
+                *velocity = [0.0, 0.0];
+            }
+        }
+        self.diffuse(&cells, parameters.viscosity * dt / cell_size.powi(2), parameters.iterations);
+        self.drag(&materials, map.materials(), dt);
+        self.project(&cells, &sources, cell_size, parameters.iterations);
+        self.advect_velocity(&cells, dt / cell_size);
+        self.project(&cells, &sources, cell_size, parameters.iterations);
+        let decay = (-parameters.decay_rate * dt).exp();
+        (0..map.fields().len()).map(|field|
+        {
+            let tiles = map.field_tiles(field, self.bounds);
+            let boundaries = map.fields()[field].boundaries;
+            let edge = |axis: usize| match boundaries[axis]
+            {
+                world::Boundary::Fixed(value) => Some(value),
+                _ => None,
+            };
+            let decay = if field == 0 { decay } else { 1.0 };
+            let (advected, budget) = self.advect_values(&tiles, &cells, &permeability, dt / cell_size,
+                                                          [edge(0), edge(1)], decay);
+            map.write_field(field, self.bounds, &advected);
+            budget
+        }).collect()
+    }
+
+    /// Sources and fixtures are solid to the flow, and feed it through the diffusion of the map.
+    fn classify(&self, tiles: &[world::Tile], materials: &[world::MaterialIndex], palette: &[world::Material])
+        -> Vec<Cell>
+    {
+        tiles.iter().zip(materials.iter()).map(|(tile, &material)| match *tile
+        {
+            world::Tile::Empty(_) if palette[material as usize].permeability > 0.0 => Cell::Fluid,
+            world::Tile::Drain => Cell::Outflow,
+            _ => Cell::Solid,
+        }).collect()
+    }
+
+    /// Target divergence of every cell, which is only non-zero for tiles holding a pressure.
+    /// Without any outflow the sources are balanced among themselves to sum to zero, as the
+    /// pressure equation has no solution otherwise.
+    fn sources(&self, tiles: &[world::Tile], source_rate: f32) -> Vec<f32>
+    {
+        let mut sources: Vec<f32> = tiles.iter()
+            .map(|tile| tile.quantity() * source_rate).collect();
+        let outflow = self.boundaries.iter().any(|&boundary| edge_cell(boundary) == Cell::Outflow) ||
+                      tiles.contains(&world::Tile::Drain);
+        if !outflow
+        {
+            let (total, count) = sources.iter().filter(|&&source| source != 0.0)
+                .fold((0.0f32, 0u32), |(total, count), &source| (total + source, count + 1));
+            if count > 0
+            {
+                let mean = total / count as f32;
+                for source in sources.iter_mut().filter(|source| **source != 0.0)
+                {
+                    *source -= mean;
+                }
+            }
+        }
+        sources
+    }
+
+    /// Slows the flow down by the drag and permeability of the material of every tile.
+    fn drag(&mut self, materials: &[world::MaterialIndex], palette: &[world::Material], dt: f32) -> ()
+    {
+        let rates: Vec<f32> = palette.iter().map(|material|
+        {
+            let porous = if material.permeability > 0.0
+            {
+                POROUS_DRAG * (1.0 - material.permeability) / material.permeability
+            }
+            else
+            {
+                0.0
+            };
+            material.drag + porous
+        }).collect();
+        if rates.iter().all(|&rate| rate == 0.0)
+        {
+            return;
+        }
+        let factors: Vec<f32> = rates.iter().map(|rate| (-rate * dt).exp()).collect();
+        for (velocity, &material) in self.velocity.iter_mut().zip(materials.iter())
+        {
+            let factor = factors[material as usize];
+            *velocity = [velocity[0] * factor, velocity[1] * factor];
+        }
+    }
+
+    fn diffuse(&mut self, cells: &[Cell], a: f32, iterations: u32) -> ()
+    {
+        if a <= 0.0
+        {
+            return;
+        }
+        let previous = self.velocity.clone();
+        let mut velocity = std::mem::take(&mut self.velocity);
+        for _ in 0..iterations
+        {
+            for colour in 0..2
+            {
+                sweep(&mut velocity, self.bounds.width, colour, |i, velocity: &[Velocity]|
+                {
+                    if cells[i] != Cell::Fluid
+                    {
+                        return None;
+                    }
+                    let mut sum = [0.0, 0.0];
+                    for &(neighbour, cell, _) in self.neighbours(i, cells).iter()
+                    {
+                        // Solid walls are no-slip, outflow lets the velocity leave unchanged.
+                        let velocity = match (neighbour, cell)
+                        {
+                            (Some(n), Cell::Fluid) => velocity[n],
+                            (_, Cell::Outflow) => velocity[i],
+                            _ => [0.0, 0.0],
+                        };
+                        sum[0] += velocity[0];
+                        sum[1] += velocity[1];
+                    }
+                    Some([(previous[i][0] + a * sum[0]) / (1.0 + 4.0 * a),
+                          (previous[i][1] + a * sum[1]) / (1.0 + 4.0 * a)])
+                });
+            }
+        }
+        self.velocity = velocity;
+    }
+
+    /// Removes the divergence not accounted for by sources, solving for a pressure scaled
+    /// by the squared cell size so the iteration works in tiles.
+    fn project(&mut self, cells: &[Cell], sources: &[f32], cell_size: f32, iterations: u32) -> ()
+    {
+        let mut divergence = vec![0.0f32; cells.len()];
+        for i in 0..cells.len()
+        {
+            if cells[i] != Cell::Fluid
+            {
+                continue;
+            }
+            let mut sum = 0.0;
+            for &(neighbour, cell, direction) in self.neighbours(i, cells).iter()
+            {
+                let axis = if direction[0] != 0 { 0 } else { 1 };
+                // The normal component is mirrored at walls so no fluid crosses them.
+                let component = match (neighbour, cell)
+                {
+                    (Some(n), Cell::Fluid) => self.velocity[n][axis],
+                    (_, Cell::Outflow) => self.velocity[i][axis],
+                    _ => -self.velocity[i][axis],
+                };
+                sum += component * direction[axis] as f32;
+            }
+            divergence[i] = (0.5 * sum * cell_size) - (sources[i] * cell_size.powi(2));
+        }
+
+        let mut pressure = vec![0.0f32; cells.len()];
+        for _ in 0..iterations
+        {
+            for colour in 0..2
+            {
+                sweep(&mut pressure, self.bounds.width, colour, |i, pressure: &[f32]|
+                {
+                    if cells[i] != Cell::Fluid
+                    {
+                        return None;
+                    }
+                    let mut sum = 0.0;
+                    let mut count = 0u32;
+                    for &(neighbour, cell, _) in self.neighbours(i, cells).iter()
+                    {
+                        match (neighbour, cell)
+                        {
+                            (Some(n), Cell::Fluid) =>
+                            {
+                                sum += pressure[n];
+                                count += 1;
+                            },
+                            (_, Cell::Outflow) => count += 1,
+                            _ => (),
+                        }
+                    }
+                    if count > 0 { Some((sum - divergence[i]) / count as f32) } else { None }
+                });
+            }
+        }
+
+        for i in 0..cells.len()
+        {
+            if cells[i] != Cell::Fluid
+            {
+                continue;
+            }
+            for &(neighbour, cell, direction) in self.neighbours(i, cells).iter()
+            {
+                let axis = if direction[0] != 0 { 0 } else { 1 };
+                let neighbour_pressure = match (neighbour, cell)
+                {
+                    (Some(n), Cell::Fluid) => pressure[n],
+                    (_, Cell::Outflow) => 0.0,
+                    _ => pressure[i],
+                };
+                self.velocity[i][axis] -= 0.5 * neighbour_pressure * direction[axis] as f32 / cell_size;
+            }
+        }
+    }
+
+    /// `scale` converts velocities into tiles per step.
+    fn advect_velocity(&mut self, cells: &[Cell], scale: f32) -> ()
+    {
+        let mut advected = self.velocity.clone();
+        for i in 0..cells.len()
+        {
+            if cells[i] == Cell::Fluid
+            {
+                advected[i] = self.sample(self.departure_point(i, scale));
+            }
+        }
+        self.velocity = advected;
+    }
+
+    /// The tiles with their values carried along by the flow and scaled by `decay`, with
+    /// what the flow carried into every drain and across the edges of the map. What flows in
+    /// across an edge holds its fixed value along that axis in `edges`, or else the value of
+    /// the tile it flows into, as the map reads past open edges.
+    ///
+    /// Every face between two tiles moves the upwind value along by the flow across it, as
+    /// much as the less permeable of the two lets through, so the values are conserved. What
+    /// leaves a tile through all of its faces is limited to what it holds, which keeps the
+    /// values from changing sign.
+    fn advect_values(&self, tiles: &[world::Tile], cells: &[Cell], permeability: &[f32], scale: f32,
+                     edges: [Option<f32>; 2], decay: f32)
+        -> (Vec<world::Tile>, budget::Budget)
+    {
+        let values: Vec<f32> = tiles.iter().zip(cells.iter()).map(|(tile, &cell)| match *tile
+        {
+            world::Tile::Empty(value) if cell == Cell::Fluid => value,
+            _ => 0.0,
+        }).collect();
+        let mut advected = values.clone();
+        let mut budget = budget::Budget::default();
+        for i in 0..cells.len()
+        {
+            if cells[i] != Cell::Fluid
+            {
+                continue;
+            }
+            // Fraction of the tile crossing each face per step, positive leaving it.
+            let neighbours = self.neighbours(i, cells);
+            let mut fractions = [0.0f32; 4];
+            for (fraction, &(neighbour, cell, direction)) in fractions.iter_mut().zip(neighbours.iter())
+            {
+                let axis = if direction[0] != 0 { 0 } else { 1 };
+                let velocity = match (neighbour, cell)
+                {
+                    (Some(n), Cell::Fluid) =>
+                        0.5 * (self.velocity[i][axis] + self.velocity[n][axis]) * permeability[i].min(permeability[n]),
+                    (Some(n), Cell::Outflow) => self.velocity[i][axis] * permeability[i].min(permeability[n]),
+                    (None, Cell::Outflow) => self.velocity[i][axis] * permeability[i],
+                    _ => 0.0,
+                };
+                *fraction = velocity * direction[axis] as f32 * scale;
+            }
+            let leaving = fractions.iter().fold(0.0f32, |leaving, &fraction| leaving + fraction.max(0.0));
+            let limit = if leaving > 1.0 { 1.0 / leaving } else { 1.0 };
+            for (&fraction, &(neighbour, cell, direction)) in fractions.iter().zip(neighbours.iter())
+            {
+                if fraction > 0.0
+                {
+                    let amount = values[i] * fraction * limit;
+                    advected[i] -= amount;
+                    match (neighbour, cell)
+                    {
+                        (Some(n), Cell::Fluid) => advected[n] += amount,
+                        (Some(n), _) => *budget.drains.entry(self.to_point(n)).or_insert(0.0) += amount as f64,
+                        (None, _) => budget.flow -= amount as f64,
+                    }
+                }
+                else if let (None, Cell::Outflow) = (neighbour, cell)
+                {
+                    // Tiles next to fluid tiles move their own values into them, but past
+                    // the edge of the map there is only the boundary.
+                    let axis = if direction[0] != 0 { 0 } else { 1 };
+                    let amount = edges[axis].unwrap_or(values[i]) * (-fraction).min(1.0);
+                    advected[i] += amount;
+                    budget.flow += amount as f64;
+                }
+            }
+        }
+        let tiles = tiles.iter().zip(advected.iter()).zip(cells.iter()).map(|((&tile, &value), &cell)|
+            if cell == Cell::Fluid
+            {
+                budget.flow -= (value * (1.0 - decay)) as f64;
+                world::Tile::Empty(value * decay)
+            }
+            else
+            {
+                tile
+            }).collect();
+        (tiles, budget)
+    }
+
+    fn departure_point(&self, index: usize, scale: f32) -> entity::Point
+    {
+        let velocity = self.velocity[index];
+        let point = self.to_point(index);
+        [point[0] as f32 + 0.5 - velocity[0] * scale,
+         point[1] as f32 + 0.5 - velocity[1] * scale]
+    }
+
+    /// The four cells surrounding a point along with their bilinear weights.
+    fn stencil(&self, point: entity::Point) -> [(Option<usize>, f32); 4]
+    {
+        let x = point[0] - 0.5;
+        let y = point[1] - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let x0 = x0 as world::Coordinate;
+        let y0 = y0 as world::Coordinate;
+        [
+            (self.to_index([x0    , y0    ]), (1.0 - fx) * (1.0 - fy)),
+            (self.to_index([x0 + 1, y0    ]), fx * (1.0 - fy)),
+            (self.to_index([x0    , y0 + 1]), (1.0 - fx) * fy),
+            (self.to_index([x0 + 1, y0 + 1]), fx * fy),
+        ]
+    }
+
+    /// The cells next to cell `index` and what they are, with the direction they lie in.
+    /// Past the edge of the flow there is only a cell when the boundary wraps around.
+    fn neighbours(&self, index: usize, cells: &[Cell]) -> [(Option<usize>, Cell, world::Point); 4]
+    {
+        // Worked out on indices, as this is the innermost loop of every solver.
+        let (width, height) = (self.bounds.width, self.bounds.height);
+        let x = index % width;
+        let y = index / width;
+        let inside = |n: usize| (Some(n), cells[n]);
+        let edge = |axis: usize, wrapped: usize| match self.boundaries[axis]
+        {
+            world::Boundary::Periodic => (Some(wrapped), cells[wrapped]),
+            boundary => (None, edge_cell(boundary)),
+        };
+        let (left, right, up, down) =
+        (
+            if x > 0 { inside(index - 1) } else { edge(0, index + width - 1) },
+            if x + 1 < width { inside(index + 1) } else { edge(0, index + 1 - width) },
+            if y > 0 { inside(index - width) } else { edge(1, index + (width * (height - 1))) },
+            if y + 1 < height { inside(index + width) } else { edge(1, index - (width * (height - 1))) },
+        );
+        [
+            (left.0, left.1, [-1, 0]),
+            (right.0, right.1, [1, 0]),
+            (up.0, up.1, [0, -1]),
+            (down.0, down.1, [0, 1]),
+        ]
+    }
+
+    fn to_point(&self, index: usize) -> world::Point
+    {
+        self.bounds.point(index)
+    }
+
+    fn to_index(&self, point: world::Point) -> Option<usize>
+    {
+        self.bounds.index(self.bounds.wrap(point, self.boundaries))
+    }
+}
+
+/// How the flow treats what lies past a boundary that doesn't wrap around.
+fn edge_cell(boundary: world::Boundary) -> Cell
+{
+    match boundary
+    {
+        world::Boundary::Walled | world::Boundary::Periodic => Cell::Solid,
+        world::Boundary::Open | world::Boundary::Fixed(_) => Cell::Outflow,
+    }
+}
+
+/// Relaxes the cells of one colour of a checkerboard over a grid `width` cells wide, the cells
+/// with `(x + y) % 2 == colour`, to what `relax` makes of them, if anything, given the values
+/// before the pass. Solvers alternate colours as in red-black Gauss-Seidel: cells only
+/// neighbour cells of the other colour, but across periodic edges of odd length, so every cell
+/// of a pass can be relaxed at once.
+#[cfg(not(feature = "parallel"))]
+fn sweep<T, F>(values: &mut [T], width: usize, colour: usize, relax: F) -> ()
+    where T: Copy + Send + Sync, F: Fn(usize, &[T]) -> Option<T> + Sync
+{
+    sweep_serially(values, width, colour, relax)
+}
+
+/// Every cell only reads from the values before the pass, so rows can be relaxed in any order
+/// with identical results.
+#[cfg(feature = "parallel")]
+fn sweep<T, F>(values: &mut [T], width: usize, colour: usize, relax: F) -> ()
+    where T: Copy + Send + Sync, F: Fn(usize, &[T]) -> Option<T> + Sync
+{
+    use rayon::prelude::*;
+
+    let previous = values.to_vec();
+    values.par_chunks_mut(width).enumerate().for_each(|(y, row)| relax_row(row, y, width, colour, &relax, &previous));
+}
+
+#[cfg(any(test, not(feature = "parallel")))]
+fn sweep_serially<T, F>(values: &mut [T], width: usize, colour: usize, relax: F) -> ()
+    where T: Copy + Send + Sync, F: Fn(usize, &[T]) -> Option<T> + Sync
+{
+    let previous = values.to_vec();
+    for (y, row) in values.chunks_mut(width).enumerate()
+    {
+        relax_row(row, y, width, colour, &relax, &previous);
+    }
+}
+
+fn relax_row<T, F>(row: &mut [T], y: usize, width: usize, colour: usize, relax: &F, previous: &[T]) -> ()
+    where T: Copy, F: Fn(usize, &[T]) -> Option<T>
+{
+    for (x, value) in row.iter_mut().enumerate().skip((y + colour) % 2).step_by(2)
+    {
+        if let Some(relaxed) = relax((y * width) + x, previous)
+        {
+            *value = relaxed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use world::{World, Tile};
+
+    #[test]
+    fn painted_pressure_spreads() -> ()
+    {
+        let mut world = World::new(60, 40);
+        world.brush(Tile::Empty(1.0), [30, 20], 5.0);
+        for _ in 0..30
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        assert!(world.at([30, 20]).unwrap().quantity() < 0.5);
+        assert!(world.at([38, 20]).unwrap().quantity() > 0.0);
+    }
+
+    #[test]
+    fn sources_feed_the_flow() -> ()
+    {
+        let mut world = World::new(60, 40);
+        world.brush(Tile::Source(1.0), [30, 20], 3.0);
+        let mut supplied = 0.0;
+        for _ in 0..50
+        {
+            world.simulate(1.0 / 60.0);
+            supplied += world.budgets()[0].sources;
+        }
+        assert_eq!(world.at([30, 20]), Some(Tile::Source(1.0)));
+        assert!(world.at([35, 20]).unwrap().quantity() > 0.0);
+        assert!(supplied > 0.0 && world.budgets()[0].total > 0.0);
+    }
+
+    #[test]
+    fn islands_flow_apart() -> ()
+    {
+        let mut world = World::unbounded();
+        world.brush(Tile::Empty(1.0), [30, 20], 5.0);
+        world.brush(Tile::Empty(1.0), [3000, 2000], 5.0);
+        for _ in 0..10
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        let regions = world.fluid.regions();
+        assert_eq!(regions.len(), 2);
+        assert!(regions.iter().map(|region| region.len()).sum::<usize>() <= 8 * 64 * 64);
+        assert!(world.at([30, 20]).unwrap().quantity() < 1.0 && world.at([3000, 2000]).unwrap().quantity() < 1.0);
+        assert!(world.at([36, 20]).unwrap().quantity() > 0.0 && world.at([3006, 2000]).unwrap().quantity() > 0.0);
+    }
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod parallel_tests
+{
+    use super::{sweep, sweep_serially};
+
+    #[test]
+    fn parallel_sweeps_match_serial() -> ()
+    {
+        // An odd width puts cells of the same colour next to each other across the wrap.
+        let (width, height) = (67, 40);
+        let relax = |i: usize, values: &[f32]|
+        {
+            let (x, y) = (i % width, i / width);
+            if (x * 7 + y * 3) % 11 == 0
+            {
+                return None;
+            }
+            let left = values[(y * width) + ((x + width - 1) % width)];
+            let right = values[(y * width) + ((x + 1) % width)];
+            let up = values[(((y + height - 1) % height) * width) + x];
+            let down = values[(((y + 1) % height) * width) + x];
+            Some((left + right + up + down + 1.0) / 4.0)
+        };
+        let mut parallel: Vec<f32> = (0..(width * height)).map(|i| ((i * 37) % 101) as f32).collect();
+        let mut serial = parallel.clone();
+        for _ in 0..20
+        {
+            for colour in 0..2
+            {
+                sweep(&mut parallel, width, colour, relax);
+                sweep_serially(&mut serial, width, colour, relax);
+            }
+        }
+        assert!(parallel == serial);
+    }
+}
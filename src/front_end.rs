@@ -5,8 +5,10 @@ use glium;
 use glium::glutin;
 use glium::Surface;
 
-use world;
-use entity;
+use physics_rs::world;
+use physics_rs::entity;
+use physics_rs::image;
+use physics_rs::activity;
 
 type Point = [f32; 2];
 type Size = [f32; 2];
@@ -109,26 +111,31 @@ impl Drop for Client
     }
 }
 
-const ENTITY_SIZE: f32 = 2.0;
-
 pub struct WorldRenderer
 {
     pub map_vertices: glium::VertexBuffer<Vertex>,
     pub map_indices: glium::index::IndexBuffer<Index>,
     pub entities_vertices: glium::VertexBuffer<Vertex>,
     pub entities_indices: glium::index::NoIndices,
+    /// Tiles of the map that are drawn, with vertices placed relative to its origin.
+    view: world::Bounds,
+    /// Field whose values are shown.
+    field: world::FieldIndex,
+    /// Set until the view has been uploaded once, as the world's dirty flags may predate it.
+    full_update: bool,
 }
 
 impl WorldRenderer
 {
-    pub fn new(display: &glium::backend::glutin::Display) -> Self
+    pub fn new(display: &glium::backend::glutin::Display, view: world::Bounds) -> Self
     {
-        const QUADS_NUMBER: usize = world::WIDTH * world::HEIGHT * 4;
-        let mut vertices: [Vertex; QUADS_NUMBER] = [Vertex::default(); QUADS_NUMBER];
-        for i in 0..(world::WIDTH * world::HEIGHT)
+        let width = view.width;
+        let tiles_number = view.len();
+        let mut vertices = vec![Vertex::default(); tiles_number * 4];
+        for i in 0..tiles_number
         {
-            let x = (i % world::WIDTH) as f32;
-            let y = (i / world::WIDTH) as f32;
+            let x = (i % width) as f32;
+            let y = (i / width) as f32;
             vertices[(i * 4) + 0].position = [x      , y      ];
             vertices[(i * 4) + 1].position = [x + 1.0, y      ];
             vertices[(i * 4) + 2].position = [x + 1.0, y + 1.0];
@@ -136,9 +143,8 @@ impl WorldRenderer
         }
         let map_vertices = glium::VertexBuffer::dynamic(display, &vertices).unwrap();
 
-        const INDICES_NUMBER: usize = world::WIDTH * world::HEIGHT * 6;
-        let mut indices: [Index; INDICES_NUMBER] = [0; INDICES_NUMBER];
-        for i in 0..(world::WIDTH * world::HEIGHT)
+        let mut indices: Vec<Index> = vec![0; tiles_number * 6];
+        for i in 0..tiles_number
         {
             indices[(i * 6) + 0] = (i as Index * 4) + 0;
             indices[(i * 6) + 1] = (i as Index * 4) + 1;
@@ -159,48 +165,121 @@ impl WorldRenderer
             map_indices,
             entities_vertices,
             entities_indices,
+            view,
+            field: 0,
+            full_update: true,
         }
     }
 
-    pub fn update(&mut self, world: &world::World, display: &glium::backend::glutin::Display) -> ()
+    pub fn view(&self) -> world::Bounds
+    {
+        self.view
+    }
+
+    pub fn field(&self) -> world::FieldIndex
     {
-        let ref mut vertices = self.map_vertices.map();
-        for i in 0..(world::WIDTH * world::HEIGHT)
+        self.field
+    }
+
+    /// Shows the values of `field` from the next update on.
+    pub fn show_field(&mut self, field: world::FieldIndex) -> ()
+    {
+        self.field = field;
+        self.full_update = true;
+    }
+
+    /// Moves the view by `offset` tiles.
+    pub fn pan(&mut self, offset: world::Point) -> ()
+    {
+        self.view.origin = [self.view.origin[0] + offset[0], self.view.origin[1] + offset[1]];
+        self.full_update = true;
+    }
+
+    /// Entities are drawn `alpha` of the way from `previous_positions` to their current
+    /// positions, so motion stays smooth when steps don't line up with frames.
+    pub fn update(&mut self, world: &world::World, previous_positions: &[entity::Point], alpha: f32,
+                  display: &glium::backend::glutin::Display) -> ()
+    {
+        let (view, field) = (self.view, self.field);
+        let entity_triangle = |i: usize| -> [Point; 3]
         {
-            let x = i % world::WIDTH;
-            let y = i / world::WIDTH;
-            let position = [x as world::Coordinate, y as world::Coordinate];
-            let color = match world.at(position)
+            let entity = &world.entities.0[i];
+            let position = match previous_positions.get(i)
             {
-                Some(tile) => match tile
-                {
-                    &world::Tile::Empty(value) =>
-                    {
-                        if value > 0.0
-                        {
-                            [value.sqrt().sqrt(), value.sqrt(), value, 1.0]
-                        }
-                        else
-                        {
-                            [value.abs().sqrt(), value.abs(), value.abs().sqrt().sqrt(), 1.0]
-                        }
-                    },
-                    &world::Tile::Wall => [0.5, 0.5, 0.5, 1.0],
-                    &world::Tile::Drain => [0.5, 0.0, 0.0, 1.0],
-                },
-                None => [0.0, 0.0, 0.0, 1.0],
+                Some(previous) => [previous[0] + (entity.position[0] - previous[0]) * alpha,
+                                   previous[1] + (entity.position[1] - previous[1]) * alpha],
+                None => entity.position,
             };
-            for j in 0..4
+            let position = [position[0] - view.origin[0] as f32, position[1] - view.origin[1] as f32];
+            image::entity_triangle(position, entity.velocity)
+        };
+        let tile_color = |point: world::Point| -> Color
+        {
+            match (world.map().field_at(field, point), world.map().material_at(point))
+            {
+                (Some(tile), Some(material)) => image::tile_color_with_material(&tile, &material),
+                _ => [0.0, 0.0, 0.0, 1.0],
+            }
+        };
+        let mut dirty = if self.full_update { None } else { world.map().activity().dirty_chunks() };
+        if let Some(chunks) = dirty
+        {
+            // Once most of the view changed, it is cheaper to upload all of it.
+            let changed: usize = chunks.iter()
+                .filter_map(|chunk| world::chunk_bounds(*chunk).intersection(&view))
+                .map(|bounds| bounds.len()).sum();
+            if changed as f32 > activity::FULL_UPDATE_FRACTION * view.len() as f32
             {
-                vertices[(i * 4) + j].color = color;
+                dirty = None;
             }
         }
+        match dirty
+        {
+            // Only the rows of the changed chunks within the view are uploaded.
+            Some(chunks) => for chunk in chunks.iter()
+            {
+                let bounds = match world::chunk_bounds(*chunk).intersection(&view)
+                {
+                    Some(bounds) => bounds,
+                    None => continue,
+                };
+                for y in 0..bounds.height
+                {
+                    let mut row = Vec::with_capacity(bounds.width * 4);
+                    let start = view.index([bounds.origin[0], bounds.origin[1] + y as world::Coordinate])
+                        .unwrap();
+                    for x in 0..bounds.width
+                    {
+                        let color = tile_color(view.point(start + x));
+                        let (x, y) = (((start + x) % view.width) as f32, ((start + x) / view.width) as f32);
+                        row.push(Vertex{position: [x      , y      ], color});
+                        row.push(Vertex{position: [x + 1.0, y      ], color});
+                        row.push(Vertex{position: [x + 1.0, y + 1.0], color});
+                        row.push(Vertex{position: [x      , y + 1.0], color});
+                    }
+                    self.map_vertices.slice((start * 4)..((start * 4) + row.len())).unwrap().write(&row);
+                }
+            },
+            None =>
+            {
+                let ref mut vertices = self.map_vertices.map();
+                for i in 0..view.len()
+                {
+                    let color = tile_color(view.point(i));
+                    for j in 0..4
+                    {
+                        vertices[(i * 4) + j].color = color;
+                    }
+                }
+            },
+        }
+        self.full_update = false;
         if world.entities.0.len() * std::mem::size_of::<Vertex>() * 3 != self.entities_vertices.get_size()
         {
             let mut vertices = Vec::new();
             for i in 0..world.entities.0.len()
             {
-                let vertices_positions = Self::get_entity_vertices_positions(&world.entities.0[i]);
+                let vertices_positions = entity_triangle(i);
                 let color = [1.0, 1.0, 1.0, 0.5];
                 let mut triangle =
                 vec!
@@ -218,44 +297,11 @@ impl WorldRenderer
             let mut vertices = self.entities_vertices.map();
             for i in 0..world.entities.0.len()
             {
-                let vertices_positions = Self::get_entity_vertices_positions(&world.entities.0[i]);
+                let vertices_positions = entity_triangle(i);
                 vertices[(i * 3) + 0].position = vertices_positions[0];
                 vertices[(i * 3) + 1].position = vertices_positions[1];
                 vertices[(i * 3) + 2].position = vertices_positions[2];
             }
         }
     }
-
-    fn get_entity_vertices_positions(entity: &entity::Entity) -> [Point; 3]
-    {
-        use entity::Point;
-        use entity::Vector;
-        let rotate_point = |[cx, cy]: Point, angle: f32, [px, py]: Point| -> Point
-        {
-            let x = angle.cos() * (px - cx) - angle.sin() * (py - cy) + cx;
-            let y = angle.sin() * (px - cx) + angle.cos() * (py - cy) + cy;
-            [x, y]
-        };
-        let normalize = |[x, y]: Vector| -> Vector
-        {
-            let length = (x.powi(2) + y.powi(2)).sqrt();
-            if length == 0.0
-            {
-                [0.0, 0.0]
-            }
-            else
-            {
-                [x / length, y / length]
-            }
-        };
-        let [ex, ey] = entity.position;
-        let center = [ex - (ENTITY_SIZE / 2.0), ey];
-        let direction = normalize(entity.velocity);
-        let angle = direction[1].atan2(direction[0]);
-        [
-            rotate_point(center, angle, entity.position),
-            rotate_point(center, angle, [ex - ENTITY_SIZE, ey - (ENTITY_SIZE / 2.0)]),
-            rotate_point(center, angle, [ex - ENTITY_SIZE, ey + (ENTITY_SIZE / 2.0)]),
-        ]
-    }
 }
@@ -0,0 +1,555 @@
+// The code base spells out unit return types.
+#![allow(clippy::unused_unit)]
+
+extern crate physics_rs;
+
+use std::io::Write;
+use std::time::Instant;
+
+use physics_rs::world;
+use physics_rs::field;
+use physics_rs::save;
+use physics_rs::image;
+use physics_rs::statistics::Statistics;
+use physics_rs::budget::Budget;
+
+const DEFAULT_WIDTH: usize = 300;
+const DEFAULT_HEIGHT: usize = 200;
+const DEFAULT_STEPS: u64 = 1000;
+const DEFAULT_TIME_STEP: f32 = 1.0 / 60.0;
+const DEFAULT_OUTPUT: &str = "statistics.csv";
+const DEFAULT_SNAPSHOT_PREFIX: &str = "snapshot";
+const DEFAULT_SNAPSHOT_SCALE: usize = 4;
+
+const USAGE: &str =
+"Usage: physics-rs-headless [OPTIONS]
+
+Runs the simulation without a display and writes per-step statistics as CSV.
+
+Options:
+    --steps N                    number of simulation steps (default 1000)
+    --dt SECONDS                 length of a simulation step (default 1/60)
+    --width N                    map width in tiles (default 300)
+    --height N                   map height in tiles (default 200)
+    --unbounded                  start from an unbounded map that grows as it is painted
+    --boundary-x MODE            boundary of a new map along x: walled, periodic, open or
+                                 fixed:VALUE (default walled)
+    --boundary-y MODE            boundary of a new map along y (default walled)
+    --field NAME,DIFFUSIVITY,FORCE_WEIGHT[,BOUNDARY_X,BOUNDARY_Y]
+                                 add a field, or change the existing one of that name such as
+                                 pressure; new fields default to open boundaries
+    --every N                    record statistics every N steps (default 1)
+    --output PATH                statistics output path (default statistics.csv)
+    --budget-output PATH         write what every step added to and took from every field
+    --drain-output PATH          write what every drain removed from every field per step
+    --load PATH                  start from a saved world instead of an empty map
+    --map PATH                   start from a map layout imported from a PNG image
+    --save PATH                  save the final world (.json for text, binary otherwise)
+    --cell-size METRES           edge length of a tile (default 0.01)
+    --transport NAME             what carries the fields: fluid, or diffusion without a flow
+                                 (default fluid)
+    --diffusion-rate D           value diffusion coefficient in m^2/s (default 0.002)
+    --pressure-response R        entity acceleration per value gradient in m^2/s^2 (default 0.36)
+    --interpolation NAME         field interpolation for entity forces, bilinear or bicubic
+    --gradient NAME              gradient operator for entity forces: interpolated, central,
+                                 eight-neighbour, sobel or scharr (default scharr)
+    --damping RATE               entity velocity decay rate per second (default 0.6)
+    --fluid-drag RATE            rate entities match the fluid velocity, per second (default 3)
+    --restitution E              restitution of entity-entity collisions (default 0.9)
+    --wall-restitution E         restitution of entity-wall collisions (default 0.8)
+    --wall-friction MU           friction coefficient of entity-wall collisions (default 0.1)
+    --coupling C                 value entities push per kg and metre travelled (default 0)
+    --viscosity V                fluid viscosity in m^2/s (default 0.00006)
+    --source-rate RATE           fluid volume released per second by a value of 1 (default 3)
+    --decay-rate RATE            pressure decay rate while advected, per second (default 0)
+    --snapshot-every N           write a PNG snapshot every N steps (default never)
+    --snapshot-prefix PREFIX     snapshot path prefix, suffixed with the step (default snapshot)
+    --snapshot-scale N           snapshot pixels per tile (default 4)
+    --snapshot-field NAME        field shown in snapshots (default pressure)
+    --pressure X,Y,RADIUS,VALUE  paint a circle of pressure VALUE
+    --value FIELD,X,Y,RADIUS,VALUE
+                                 paint a circle of VALUE into a field
+    --wall X,Y,RADIUS            paint a circle of walls
+    --drain X,Y,RADIUS           paint a circle of drains
+    --source X,Y,RADIUS,VALUE    paint a circle of sources holding VALUE
+    --emitter X,Y,RADIUS,RATE    paint a circle of emitters adding RATE per second
+    --sink X,Y,RADIUS,RATE       paint a circle of sinks taking up to RATE per second
+    --material X,Y,RADIUS,DIFFUSIVITY,PERMEABILITY,DRAG
+                                 paint a circle of a material
+    --entity X,Y                 place an entity
+    --entity-grid SPACING        place an entity on every SPACING-th empty tile
+    --help                       print this message
+
+Physical parameters override those of a loaded world. Initial conditions are applied in
+the order they are given.";
+
+enum Parameter
+{
+    CellSize,
+    DiffusionRate,
+    PressureResponse,
+    Damping,
+    FluidDrag,
+    EntityRestitution,
+    WallRestitution,
+    WallFriction,
+    EntityCoupling,
+    Viscosity,
+    SourceRate,
+    DecayRate,
+}
+
+enum InitialCondition
+{
+    Brush(world::Tile, world::Point, f32),
+    Value(String, world::Point, f32, f32),
+    Material(world::Material, world::Point, f32),
+    Entity(world::Point),
+    EntityGrid(usize),
+}
+
+struct Options
+{
+    steps: u64,
+    dt: f32,
+    width: usize,
+    height: usize,
+    unbounded: bool,
+    boundaries: [world::Boundary; 2],
+    fields: Vec<world::Field>,
+    every: u64,
+    output: String,
+    budget_output: Option<String>,
+    drain_output: Option<String>,
+    load: Option<String>,
+    map: Option<String>,
+    save: Option<String>,
+    parameters: Vec<(Parameter, f32)>,
+    transport: Option<world::Transport>,
+    interpolation: Option<field::Interpolation>,
+    gradient_operator: Option<field::GradientOperator>,
+    snapshot_every: Option<u64>,
+    snapshot_prefix: String,
+    snapshot_scale: usize,
+    snapshot_field: Option<String>,
+    initial_conditions: Vec<InitialCondition>,
+}
+
+impl Options
+{
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String>
+    {
+        let mut options = Self
+        {
+            steps: DEFAULT_STEPS,
+            dt: DEFAULT_TIME_STEP,
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            unbounded: false,
+            boundaries: [world::Boundary::Walled; 2],
+            fields: Vec::new(),
+            every: 1,
+            output: DEFAULT_OUTPUT.to_string(),
+            budget_output: None,
+            drain_output: None,
+            load: None,
+            map: None,
+            save: None,
+            parameters: Vec::new(),
+            transport: None,
+            interpolation: None,
+            gradient_operator: None,
+            snapshot_every: None,
+            snapshot_prefix: DEFAULT_SNAPSHOT_PREFIX.to_string(),
+            snapshot_scale: DEFAULT_SNAPSHOT_SCALE,
+            snapshot_field: None,
+            initial_conditions: Vec::new(),
+        };
+        while let Some(arg) = args.next()
+        {
+            if arg == "--help"
+            {
+                return Err(String::new());
+            }
+            if arg == "--unbounded"
+            {
+                options.unbounded = true;
+                continue;
+            }
+            let value = args.next().ok_or(format!("Missing value for {}.", arg))?;
+            match arg.as_str()
+            {
+                "--steps" => options.steps = parse_number(&arg, &value)?,
+                "--dt" => options.dt = parse_number(&arg, &value)?,
+                "--width" => options.width = parse_number(&arg, &value)?,
+                "--height" => options.height = parse_number(&arg, &value)?,
+                "--boundary-x" => options.boundaries[0] = value.parse()?,
+                "--boundary-y" => options.boundaries[1] = value.parse()?,
+                "--field" => options.fields.push(value.parse()?),
+                "--every" => options.every = parse_number(&arg, &value)?,
+                "--output" => options.output = value,
+                "--budget-output" => options.budget_output = Some(value),
+                "--drain-output" => options.drain_output = Some(value),
+                "--load" => options.load = Some(value),
+                "--map" => options.map = Some(value),
+                "--save" => options.save = Some(value),
+                "--cell-size" =>
+                    options.parameters.push((Parameter::CellSize, parse_number(&arg, &value)?)),
+                "--diffusion-rate" =>
+                    options.parameters.push((Parameter::DiffusionRate, parse_number(&arg, &value)?)),
+                "--pressure-response" =>
+                    options.parameters.push((Parameter::PressureResponse, parse_number(&arg, &value)?)),
+                "--transport" => options.transport = Some(value.parse()?),
+                "--interpolation" => options.interpolation = Some(value.parse()?),
+                "--gradient" => options.gradient_operator = Some(value.parse()?),
+                "--damping" =>
+                    options.parameters.push((Parameter::Damping, parse_number(&arg, &value)?)),
+                "--fluid-drag" =>
+                    options.parameters.push((Parameter::FluidDrag, parse_number(&arg, &value)?)),
+                "--restitution" =>
+                    options.parameters.push((Parameter::EntityRestitution, parse_number(&arg, &value)?)),
+                "--wall-restitution" =>
+                    options.parameters.push((Parameter::WallRestitution, parse_number(&arg, &value)?)),
+                "--wall-friction" =>
+                    options.parameters.push((Parameter::WallFriction, parse_number(&arg, &value)?)),
+                "--coupling" =>
+                    options.parameters.push((Parameter::EntityCoupling, parse_number(&arg, &value)?)),
+                "--viscosity" =>
+                    options.parameters.push((Parameter::Viscosity, parse_number(&arg, &value)?)),
+                "--source-rate" =>
+                    options.parameters.push((Parameter::SourceRate, parse_number(&arg, &value)?)),
+                "--decay-rate" =>
+                    options.parameters.push((Parameter::DecayRate, parse_number(&arg, &value)?)),
+                "--snapshot-every" => options.snapshot_every = Some(parse_number(&arg, &value)?),
+                "--snapshot-prefix" => options.snapshot_prefix = value,
+                "--snapshot-scale" => options.snapshot_scale = parse_number(&arg, &value)?,
+                "--snapshot-field" => options.snapshot_field = Some(value),
+                "--pressure" =>
+                {
+                    let [x, y, radius, value] = parse_list::<[f32; 4]>(&arg, &value)?;
+                    options.initial_conditions.push(
+                        InitialCondition::Brush(world::Tile::Empty(value), to_point(x, y), radius));
+                },
+                "--value" =>
+                {
+                    let comma = value.find(',').ok_or(format!("{} expects a field name first.", arg))?;
+                    let (name, list) = value.split_at(comma);
+                    let [x, y, radius, amount] = parse_list::<[f32; 4]>(&arg, &list[1..])?;
+                    options.initial_conditions.push(
+                        InitialCondition::Value(name.to_string(), to_point(x, y), radius, amount));
+                },
+                "--wall" =>
+                {
+                    let [x, y, radius] = parse_list::<[f32; 3]>(&arg, &value)?;
+                    options.initial_conditions.push(
+                        InitialCondition::Brush(world::Tile::Wall, to_point(x, y), radius));
+                },
+                "--drain" =>
+                {
+                    let [x, y, radius] = parse_list::<[f32; 3]>(&arg, &value)?;
+                    options.initial_conditions.push(
+                        InitialCondition::Brush(world::Tile::Drain, to_point(x, y), radius));
+                },
+                "--source" =>
+                {
+                    let [x, y, radius, value] = parse_list::<[f32; 4]>(&arg, &value)?;
+                    options.initial_conditions.push(
+                        InitialCondition::Brush(world::Tile::Source(value), to_point(x, y), radius));
+                },
+                "--emitter" =>
+                {
+                    let [x, y, radius, rate] = parse_list::<[f32; 4]>(&arg, &value)?;
+                    if rate < 0.0 || rate.is_nan()
+                    {
+                        return Err("--emitter needs a non-negative rate.".to_string());
+                    }
+                    options.initial_conditions.push(
+                        InitialCondition::Brush(world::Tile::Emitter(rate), to_point(x, y), radius));
+                },
+                "--sink" =>
+                {
+                    let [x, y, radius, rate] = parse_list::<[f32; 4]>(&arg, &value)?;
+                    if rate < 0.0 || rate.is_nan()
+                    {
+                        return Err("--sink needs a non-negative rate.".to_string());
+                    }
+                    options.initial_conditions.push(
+                        InitialCondition::Brush(world::Tile::Sink(rate), to_point(x, y), radius));
+                },
+                "--material" =>
+                {
+                    let [x, y, radius, diffusivity, permeability, drag] = parse_list::<[f32; 6]>(&arg, &value)?;
+                    let material = world::Material{diffusivity, permeability, drag};
+                    material.validate().map_err(|error| format!("--material: {}.", error))?;
+                    options.initial_conditions.push(InitialCondition::Material(material, to_point(x, y), radius));
+                },
+                "--entity" =>
+                {
+                    let [x, y] = parse_list::<[f32; 2]>(&arg, &value)?;
+                    options.initial_conditions.push(InitialCondition::Entity(to_point(x, y)));
+                },
+                "--entity-grid" =>
+                {
+                    let spacing = parse_number(&arg, &value)?;
+                    if spacing == 0
+                    {
+                        return Err("--entity-grid spacing must be positive.".to_string());
+                    }
+                    options.initial_conditions.push(InitialCondition::EntityGrid(spacing));
+                },
+                _ => return Err(format!("Unknown option {}.", arg)),
+            }
+        }
+        if options.width == 0 || options.height == 0
+        {
+            return Err("Map dimensions must be non-zero.".to_string());
+        }
+        if options.load.is_some() && options.map.is_some()
+        {
+            return Err("--load and --map are mutually exclusive.".to_string());
+        }
+        if options.dt.is_nan() || options.dt <= 0.0
+        {
+            return Err("--dt must be positive.".to_string());
+        }
+        for &(ref parameter, value) in options.parameters.iter()
+        {
+            if let Parameter::CellSize = *parameter
+            {
+                if value.is_nan() || value <= 0.0
+                {
+                    return Err("--cell-size must be positive.".to_string());
+                }
+            }
+        }
+        if options.every == 0
+        {
+            return Err("--every must be positive.".to_string());
+        }
+        if options.snapshot_every == Some(0) || options.snapshot_scale == 0
+        {
+            return Err("Snapshot interval and scale must be positive.".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String>
+{
+    value.parse().map_err(|_| format!("Invalid value {} for {}.", value, option))
+}
+
+fn parse_list<T: Default + AsMut<[f32]>>(option: &str, value: &str) -> Result<T, String>
+{
+    let mut list = T::default();
+    let parts: Vec<&str> = value.split(',').collect();
+    if parts.len() != list.as_mut().len()
+    {
+        return Err(format!("{} expects {} comma-separated values.", option, list.as_mut().len()));
+    }
+    for (i, part) in parts.iter().enumerate()
+    {
+        list.as_mut()[i] = parse_number(option, part.trim())?;
+    }
+    Ok(list)
+}
+
+fn to_point(x: f32, y: f32) -> world::Point
+{
+    [x as world::Coordinate, y as world::Coordinate]
+}
+
+fn apply(world: &mut world::World, condition: &InitialCondition) -> Result<(), String>
+{
+    match *condition
+    {
+        InitialCondition::Brush(tile, position, radius) => world.brush(tile, position, radius),
+        InitialCondition::Value(ref name, position, radius, value) =>
+            world.brush_field(field_index(world, name)?, world::Tile::Empty(value), position, radius),
+        InitialCondition::Material(material, position, radius) => world.paint_material(material, position, radius)?,
+        InitialCondition::Entity(position) => world.place_entity(position),
+        InitialCondition::EntityGrid(spacing) =>
+        {
+            let bounds = world.bounds();
+            for y in (0..bounds.height).step_by(spacing)
+            {
+                for x in (0..bounds.width).step_by(spacing)
+                {
+                    let position = [bounds.origin[0] + x as world::Coordinate,
+                                    bounds.origin[1] + y as world::Coordinate];
+                    if let Some(world::Tile::Empty(_)) = world.at(position)
+                    {
+                        world.place_entity(position);
+                    }
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+fn field_index(world: &world::World, name: &str) -> Result<world::FieldIndex, String>
+{
+    world.map().field_index(name).ok_or(format!("Unknown field {}.", name))
+}
+
+fn run(options: Options) -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut world = match (options.load.as_ref(), options.map.as_ref())
+    {
+        (Some(path), _) => save::load(path)?,
+        (_, Some(path)) => world::World::from_map(image::load_map(path)?),
+        _ if options.unbounded => world::World::unbounded(),
+        _ => world::World::with_boundaries(options.width, options.height, options.boundaries),
+    };
+    if let Some(transport) = options.transport
+    {
+        world.parameters.transport = transport;
+    }
+    if let Some(interpolation) = options.interpolation
+    {
+        world.parameters.interpolation = interpolation;
+    }
+    if let Some(gradient_operator) = options.gradient_operator
+    {
+        world.parameters.gradient_operator = gradient_operator;
+    }
+    for &(ref parameter, value) in options.parameters.iter()
+    {
+        match *parameter
+        {
+            Parameter::CellSize => world.parameters.cell_size = value,
+            Parameter::DiffusionRate => world.parameters.diffusion_rate = value,
+            Parameter::PressureResponse => world.parameters.pressure_response = value,
+            Parameter::Damping => world.parameters.damping = value,
+            Parameter::FluidDrag => world.parameters.fluid_drag = value,
+            Parameter::EntityRestitution => world.parameters.entity_restitution = value,
+            Parameter::WallRestitution => world.parameters.wall_restitution = value,
+            Parameter::WallFriction => world.parameters.wall_friction = value,
+            Parameter::EntityCoupling => world.parameters.entity_coupling = value,
+            Parameter::Viscosity => world.fluid.parameters.viscosity = value,
+            Parameter::SourceRate => world.fluid.parameters.source_rate = value,
+            Parameter::DecayRate => world.fluid.parameters.decay_rate = value,
+        }
+    }
+    for field in options.fields.iter()
+    {
+        match world.map().field_index(&field.name)
+        {
+            Some(index) => world.set_field(index, field.clone()),
+            None => { world.add_field(field.clone()); },
+        }
+    }
+    for condition in options.initial_conditions.iter()
+    {
+        apply(&mut world, condition)?;
+    }
+    let snapshot_field = match options.snapshot_field
+    {
+        Some(ref name) => field_index(&world, name)?,
+        None => 0,
+    };
+
+    let file = std::fs::File::create(&options.output)?;
+    let mut output = std::io::BufWriter::new(file);
+    writeln!(output, "step,time,{}", Statistics::csv_header())?;
+    writeln!(output, "0,0,{}", Statistics::new(&world).to_csv())?;
+    let mut budget_output = match options.budget_output
+    {
+        Some(ref path) =>
+        {
+            let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+            writeln!(writer, "step,time,field,{}", Budget::csv_header())?;
+            Some(writer)
+        },
+        None => None,
+    };
+    let mut drain_output = match options.drain_output
+    {
+        Some(ref path) =>
+        {
+            let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+            writeln!(writer, "step,time,field,x,y,drained")?;
+            Some(writer)
+        },
+        None => None,
+    };
+    let snapshot = |world: &world::World, step: u64| -> Result<(), image::Error>
+    {
+        match options.snapshot_every
+        {
+            Some(every) if step.is_multiple_of(every) =>
+            {
+                let path = format!("{}-{:06}.png", options.snapshot_prefix, step);
+                image::save_snapshot(world, snapshot_field, path, options.snapshot_scale)
+            },
+            _ => Ok(()),
+        }
+    };
+    snapshot(&world, 0)?;
+
+    let start = Instant::now();
+    for step in 1..(options.steps + 1)
+    {
+        world.simulate(options.dt);
+        if step % options.every == 0 || step == options.steps
+        {
+            let time = step as f32 * options.dt;
+            writeln!(output, "{},{},{}", step, time, Statistics::new(&world).to_csv())?;
+            for (field, budget) in world.map().fields().iter().zip(world.budgets().iter())
+            {
+                if let Some(ref mut writer) = budget_output
+                {
+                    writeln!(writer, "{},{},{},{}", step, time, field.name, budget.to_csv())?;
+                }
+                if let Some(ref mut writer) = drain_output
+                {
+                    for (position, drained) in budget.drains.iter()
+                    {
+                        writeln!(writer, "{},{},{},{},{},{}", step, time, field.name, position[0], position[1],
+                                 drained)?;
+                    }
+                }
+            }
+        }
+        snapshot(&world, step)?;
+    }
+    output.flush()?;
+    for writer in budget_output.iter_mut().chain(drain_output.iter_mut())
+    {
+        writer.flush()?;
+    }
+    if let Some(ref path) = options.save
+    {
+        save::save(&world, path, save::Format::from_path(path))?;
+    }
+
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    println!("simulated {} steps of a {}x{} map in {:.3}s, statistics written to {}",
+             options.steps, world.bounds().width, world.bounds().height, seconds, options.output);
+    Ok(())
+}
+
+fn main()
+{
+    let options = match Options::parse(std::env::args().skip(1))
+    {
+        Ok(options) => options,
+        Err(message) =>
+        {
+            if message.is_empty()
+            {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            eprintln!("{}\n\n{}", message, USAGE);
+            std::process::exit(2);
+        },
+    };
+    if let Err(error) = run(options)
+    {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
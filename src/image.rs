@@ -0,0 +1,370 @@
+//! Conversion between worlds and PNG images, and the tile palette shared with the viewer.
+//!
+//! When importing, every pixel becomes one tile, read with the palette the viewer and the
+//! snapshots draw tiles with, so that a snapshot imports as the map it shows:
+//!
+//! * grey pixels (around `[128, 128, 128]`) are `Tile::Wall`,
+//! * dark red pixels (around `[128, 0, 0]`) are `Tile::Drain`,
+//! * anything else is `Tile::Empty`, holding minus the square of the red channel when there
+//!   is more blue than red, and the square of the green channel otherwise, scaled to
+//!   `0.0..=1.0`, so black is zero and white is `1.0`,
+//! * fully transparent pixels are `Tile::Empty(0.0)`.
+//!
+//! Sources, emitters, sinks and materials aren't imported, and values beyond `1.0` either way
+//! read as `1.0`.
+
+use std;
+
+use png;
+
+use world;
+use entity;
+
+type Pixel = [u8; 3];
+pub type Color = [f32; 4];
+
+pub const ENTITY_SIZE: f32 = 2.0;
+const ENTITY_COLOR: Color = [1.0, 1.0, 1.0, 0.5];
+const BACKGROUND_COLOR: Color = [0.0, 0.0, 0.0, 1.0];
+/// How far, per channel, a pixel may be from the color of a wall or drain and still be read as
+/// one.
+const TOLERANCE: f32 = 16.0;
+
+#[derive(Debug)]
+pub enum Error
+{
+    Io(std::io::Error),
+    Decoding(png::DecodingError),
+    Encoding(png::EncodingError),
+    Empty,
+}
+
+impl std::fmt::Display for Error
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match *self
+        {
+            Error::Io(ref error) => write!(f, "I/O error: {}", error),
+            Error::Decoding(ref error) => write!(f, "invalid PNG: {}", error),
+            Error::Encoding(ref error) => write!(f, "couldn't encode PNG: {}", error),
+            Error::Empty => write!(f, "image has no pixels"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error
+{
+    fn from(error: std::io::Error) -> Self
+    {
+        Error::Io(error)
+    }
+}
+
+impl From<png::DecodingError> for Error
+{
+    fn from(error: png::DecodingError) -> Self
+    {
+        Error::Decoding(error)
+    }
+}
+
+impl From<png::EncodingError> for Error
+{
+    fn from(error: png::EncodingError) -> Self
+    {
+        Error::Encoding(error)
+    }
+}
+
+/// The tile `tile_color` draws as `color`, give or take `TOLERANCE` for walls and drains.
+pub fn tile_from_color([r, g, b]: Pixel) -> world::Tile
+{
+    let is_near = |tile: world::Tile|
+    {
+        let color = tile_color(&tile);
+        [r, g, b].iter().zip(color.iter())
+            .all(|(&channel, &target)| (channel as f32 - target * 255.0).abs() <= TOLERANCE)
+    };
+    if is_near(world::Tile::Wall)
+    {
+        world::Tile::Wall
+    }
+    else if is_near(world::Tile::Drain)
+    {
+        world::Tile::Drain
+    }
+    else if b > r
+    {
+        world::Tile::Empty(-(r as f32 / 255.0).powi(2))
+    }
+    else
+    {
+        world::Tile::Empty((g as f32 / 255.0).powi(2))
+    }
+}
+
+pub fn load_map<P: AsRef<std::path::Path>>(path: P) -> Result<world::Map, Error>
+{
+    let file = std::fs::File::open(path)?;
+    let mut decoder = png::Decoder::new(std::io::BufReader::new(file));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let width = info.width as usize;
+    let height = info.height as usize;
+    if width == 0 || height == 0
+    {
+        return Err(Error::Empty);
+    }
+
+    let channels = info.color_type.samples();
+    let mut map = world::Map::new(width, height);
+    for y in 0..height
+    {
+        let row = &buffer[(y * info.line_size)..((y + 1) * info.line_size)];
+        for x in 0..width
+        {
+            let pixel = &row[(x * channels)..((x + 1) * channels)];
+            let (color, alpha) = match info.color_type
+            {
+                png::ColorType::Grayscale => ([pixel[0]; 3], 255),
+                png::ColorType::GrayscaleAlpha => ([pixel[0]; 3], pixel[1]),
+                png::ColorType::Rgba => ([pixel[0], pixel[1], pixel[2]], pixel[3]),
+                _ => ([pixel[0], pixel[1], pixel[2]], 255),
+            };
+            let tile = if alpha == 0 { world::Tile::Empty(0.0) } else { tile_from_color(color) };
+            *map.at_mut([x as world::Coordinate, y as world::Coordinate]).unwrap() = tile;
+        }
+    }
+    Ok(map)
+}
+
+pub fn tile_color(tile: &world::Tile) -> Color
+{
+    match *tile
+    {
+        world::Tile::Empty(value) =>
+        {
+            if value > 0.0
+            {
+                [value.sqrt().sqrt(), value.sqrt(), value, 1.0]
+            }
+            else
+            {
+                [value.abs().sqrt(), value.abs(), value.abs().sqrt().sqrt(), 1.0]
+            }
+        },
+        world::Tile::Wall => [0.5, 0.5, 0.5, 1.0],
+        world::Tile::Drain => [0.5, 0.0, 0.0, 1.0],
+        world::Tile::Source(_) => [1.0, 1.0, 0.0, 1.0],
+        world::Tile::Emitter(_) => [0.0, 1.0, 1.0, 1.0],
+        world::Tile::Sink(_) => [0.6, 0.0, 0.8, 1.0],
+    }
+}
+
+/// Color of `tile` filled with `material`. Empty tiles are tinted blue by a low permeability,
+/// green by drag and grey by a low diffusivity, so painted materials show up.
+pub fn tile_color_with_material(tile: &world::Tile, material: &world::Material) -> Color
+{
+    let color = tile_color(tile);
+    match tile
+    {
+        &world::Tile::Empty(_) =>
+        {
+            let grey = 0.15 * (1.0 - material.diffusivity.min(1.0));
+            let tint = [grey,
+                        grey + 0.2 * (material.drag / 10.0).min(1.0),
+                        grey + 0.3 * (1.0 - material.permeability.min(1.0))];
+            [(color[0] + tint[0]).min(1.0), (color[1] + tint[1]).min(1.0), (color[2] + tint[2]).min(1.0), color[3]]
+        },
+        _ => color,
+    }
+}
+
+/// The triangle an entity at `position` is drawn as, pointing along its velocity.
+pub fn entity_triangle(position: entity::Point, velocity: entity::Vector) -> [entity::Point; 3]
+{
+    use entity::Point;
+    use entity::Vector;
+    let rotate_point = |[cx, cy]: Point, angle: f32, [px, py]: Point| -> Point
+    {
+        let x = angle.cos() * (px - cx) - angle.sin() * (py - cy) + cx;
+        let y = angle.sin() * (px - cx) + angle.cos() * (py - cy) + cy;
+        [x, y]
+    };
+    let normalize = |[x, y]: Vector| -> Vector
+    {
+        let length = (x.powi(2) + y.powi(2)).sqrt();
+        if length == 0.0
+        {
+            [0.0, 0.0]
+        }
+        else
+        {
+            [x / length, y / length]
+        }
+    };
+    let [ex, ey] = position;
+    let center = [ex - (ENTITY_SIZE / 2.0), ey];
+    let direction = normalize(velocity);
+    let angle = direction[1].atan2(direction[0]);
+    [
+        rotate_point(center, angle, position),
+        rotate_point(center, angle, [ex - ENTITY_SIZE, ey - (ENTITY_SIZE / 2.0)]),
+        rotate_point(center, angle, [ex - ENTITY_SIZE, ey + (ENTITY_SIZE / 2.0)]),
+    ]
+}
+
+/// Renders the bounds of the world into an RGBA buffer with `scale` pixels per tile, showing
+/// the values of `field` with the same colors as the viewer. An unbounded world without any
+/// loaded chunk renders as the blank chunk at the origin.
+pub fn render(world: &world::World, field: world::FieldIndex, scale: usize) -> (Vec<u8>, usize, usize)
+{
+    let bounds = match world.bounds()
+    {
+        bounds if bounds.width == 0 || bounds.height == 0 => world::chunk_bounds([0, 0]),
+        bounds => bounds,
+    };
+    let width = bounds.width * scale;
+    let height = bounds.height * scale;
+    let mut colors = vec![BACKGROUND_COLOR; width * height];
+    for y in 0..height
+    {
+        for x in 0..width
+        {
+            let position = [bounds.origin[0] + (x / scale) as world::Coordinate,
+                            bounds.origin[1] + (y / scale) as world::Coordinate];
+            if let (Some(tile), Some(material)) =
+                (world.map().field_at(field, position), world.map().material_at(position))
+            {
+                colors[(y * width) + x] = tile_color_with_material(&tile, &material);
+            }
+        }
+    }
+
+    for entity in world.entities.0.iter()
+    {
+        let position = [entity.position[0] - bounds.origin[0] as f32,
+                        entity.position[1] - bounds.origin[1] as f32];
+        let triangle = entity_triangle(position, entity.velocity);
+        let edge = |[ax, ay]: entity::Point, [bx, by]: entity::Point, [px, py]: entity::Point|
+            (bx - ax) * (py - ay) - (by - ay) * (px - ax);
+        let area = edge(triangle[0], triangle[1], triangle[2]);
+        if area == 0.0
+        {
+            continue;
+        }
+        let mut min = [f32::INFINITY; 2];
+        let mut max = [f32::NEG_INFINITY; 2];
+        for vertex in triangle.iter()
+        {
+            for axis in 0..2
+            {
+                min[axis] = min[axis].min(vertex[axis]);
+                max[axis] = max[axis].max(vertex[axis]);
+            }
+        }
+        let to_pixel = |coordinate: f32, limit: usize|
+            ((coordinate * scale as f32).max(0.0) as usize).min(limit);
+        for y in to_pixel(min[1], height)..to_pixel(max[1] + 1.0, height)
+        {
+            for x in to_pixel(min[0], width)..to_pixel(max[0] + 1.0, width)
+            {
+                let point = [(x as f32 + 0.5) / scale as f32, (y as f32 + 0.5) / scale as f32];
+                let weights = [edge(triangle[1], triangle[2], point) / area,
+                               edge(triangle[2], triangle[0], point) / area,
+                               edge(triangle[0], triangle[1], point) / area];
+                if weights.iter().all(|&weight| weight >= 0.0)
+                {
+                    let color = &mut colors[(y * width) + x];
+                    let alpha = ENTITY_COLOR[3];
+                    for channel in 0..3
+                    {
+                        color[channel] = ENTITY_COLOR[channel] * alpha + color[channel] * (1.0 - alpha);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(width * height * 4);
+    for color in colors.iter()
+    {
+        for channel in color.iter()
+        {
+            data.push((channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+        }
+    }
+    (data, width, height)
+}
+
+pub fn save_snapshot<P: AsRef<std::path::Path>>(world: &world::World, field: world::FieldIndex, path: P,
+                                                scale: usize) -> Result<(), Error>
+{
+    let (data, width, height) = render(world, field, scale);
+    if width == 0 || height == 0
+    {
+        return Err(Error::Empty);
+    }
+    let file = std::fs::File::create(path)?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn snapshots_import_as_the_map_they_show() -> ()
+    {
+        let mut map = world::Map::new(12, 8);
+        let tiles = [world::Tile::Wall, world::Tile::Drain, world::Tile::Empty(0.0), world::Tile::Empty(0.02),
+                     world::Tile::Empty(0.3), world::Tile::Empty(0.85), world::Tile::Empty(-0.1),
+                     world::Tile::Empty(-0.6)];
+        for (x, &tile) in tiles.iter().enumerate()
+        {
+            *map.at_mut([x as world::Coordinate + 2, 3]).unwrap() = tile;
+        }
+        let world = world::World::from_map(map);
+        let path = std::env::temp_dir().join(format!("physics-rs-snapshot-{}.png", std::process::id()));
+        save_snapshot(&world, 0, &path, 1).unwrap();
+        let imported = world::World::from_map(load_map(&path).unwrap());
+        // What was imported once exports to the same colors, so it imports as it was.
+        save_snapshot(&imported, 0, &path, 1).unwrap();
+        let reimported = load_map(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for y in 0..8
+        {
+            for x in 0..12
+            {
+                let point = [x, y];
+                assert_eq!(reimported.at(point), imported.at(point), "at {:?}", point);
+                match (world.at(point).unwrap(), imported.at(point).unwrap())
+                {
+                    (world::Tile::Empty(expected), world::Tile::Empty(value)) =>
+                        assert!((value - expected).abs() < 0.01, "{} instead of {} at {:?}", value, expected, point),
+                    (expected, tile) => assert_eq!(tile, expected, "at {:?}", point),
+                }
+            }
+        }
+    }
+    #[test]
+    fn empty_unbounded_worlds_have_snapshots() -> ()
+    {
+        let world = world::World::unbounded();
+        assert_eq!(world.bounds().width, 0);
+        let (data, width, height) = render(&world, 0, 2);
+        assert_eq!((width, height), (2 * world::CHUNK_SIZE, 2 * world::CHUNK_SIZE));
+        assert!(data.chunks(4).all(|pixel| pixel == [0, 0, 0, 255]));
+    }
+}
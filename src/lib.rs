@@ -0,0 +1,22 @@
+// The code base spells out unit return types.
+#![allow(clippy::unused_unit)]
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
+extern crate serde_json;
+extern crate png;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+
+pub mod world;
+pub mod entity;
+pub mod fluid;
+pub mod field;
+pub mod spatial_hash;
+pub mod activity;
+pub mod budget;
+pub mod statistics;
+pub mod save;
+pub mod image;
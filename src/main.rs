@@ -3,11 +3,14 @@
 extern crate time;
 #[macro_use]
 extern crate glium;
+extern crate physics_rs;
 
-mod world;
-mod entity;
 mod front_end;
 
+use physics_rs::world;
+use physics_rs::save;
+use physics_rs::image;
+
 use time::PreciseTime;
 
 use glium::glutin::Event;
@@ -17,24 +20,105 @@ use glium::glutin::VirtualKeyCode;
 use glium::glutin::MouseButton;
 use glium::glutin::KeyboardInput;
 
+const DEFAULT_WIDTH: usize = 300;
+const DEFAULT_HEIGHT: usize = 200;
+/// Tiles the view moves per arrow key press.
+const PAN_STEP: world::Coordinate = 16;
+const QUICKSAVE_BINARY: &str = "quicksave.phrs";
+const QUICKSAVE_TEXT: &str = "quicksave.json";
+const SNAPSHOT_SCALE: usize = 4;
+const DEFAULT_SIMULATION_RATE: f32 = 60.0;
+const MIN_SPEED: f32 = 0.25;
+const MAX_SPEED: f32 = 16.0;
+/// Longest frame that is caught up on, so a stall doesn't snowball into ever longer frames.
+const MAX_FRAME_TIME: f32 = 0.25;
+/// Entity coupling toggled on by the C key.
+const ENTITY_COUPLING: f32 = 50.0;
+/// Value held by painted sources.
+const SOURCE_VALUE: f32 = 1.0;
+/// Value per second added by painted emitters and taken by painted sinks.
+const FIXTURE_RATE: f32 = 20.0;
+/// Materials painted by the M key, selected with the number keys: open space, a membrane,
+/// a slow medium and a porous obstacle.
+const MATERIALS: [world::Material; 4] =
+    [world::Material{diffusivity: 1.0, permeability: 1.0, drag: 0.0},
+     world::Material{diffusivity: 0.05, permeability: 0.02, drag: 0.0},
+     world::Material{diffusivity: 0.2, permeability: 1.0, drag: 5.0},
+     world::Material{diffusivity: 1.0, permeability: 0.5, drag: 20.0}];
+
 fn main()
 {
-    let mut client = front_end::Client::new();
-    let mut world_renderer = front_end::WorldRenderer::new(&client.display);
-    let mut world = world::World::new();
-    let window_size = client.window_size();
-    let scale = [window_size[0] / world::WIDTH as f32, window_size[1] / world::HEIGHT as f32];
-
-    let matrix: [[f32; 3]; 3] =
-       [[scale[0], 0.0, 0.0],
-        [0.0, scale[1], 0.0],
-        [0.0, 0.0, 1.0]];
-
-    let uniforms = uniform!
+    let mut args: Vec<String> = Vec::new();
+    let mut simulation_rate = DEFAULT_SIMULATION_RATE;
+    let mut unbounded = false;
+    let mut boundaries = [world::Boundary::Walled; 2];
+    let mut fields: Vec<world::Field> = Vec::new();
+    let mut transport = None;
+    let mut arg_iterator = std::env::args().skip(1);
+    while let Some(arg) = arg_iterator.next()
     {
-        matrix: matrix,
-        screen_size: window_size,
+        if arg == "--rate"
+        {
+            simulation_rate = arg_iterator.next().and_then(|rate| rate.parse().ok())
+                .expect("Invalid simulation rate.");
+            assert!(simulation_rate > 0.0, "Simulation rate must be positive.");
+        }
+        else if arg == "--unbounded"
+        {
+            unbounded = true;
+        }
+        else if arg == "--boundary-x" || arg == "--boundary-y"
+        {
+            let axis = if arg == "--boundary-x" { 0 } else { 1 };
+            boundaries[axis] = arg_iterator.next().and_then(|mode| mode.parse().ok())
+                .expect("Invalid boundary.");
+        }
+        else if arg == "--field"
+        {
+            fields.push(arg_iterator.next().and_then(|field| field.parse().ok()).expect("Invalid field."));
+        }
+        else if arg == "--transport"
+        {
+            transport = Some(arg_iterator.next().and_then(|transport| transport.parse().ok())
+                .expect("Invalid transport."));
+        }
+        else
+        {
+            args.push(arg);
+        }
+    }
+    let time_step = 1.0 / simulation_rate;
+    let map_image = args.iter().find(|arg| arg.ends_with(".png")).cloned();
+    let mut world = match args.len()
+    {
+        0 if unbounded => world::World::unbounded(),
+        0 => world::World::with_boundaries(DEFAULT_WIDTH, DEFAULT_HEIGHT, boundaries),
+        1 => match map_image
+        {
+            Some(ref path) => world::World::from_map(image::load_map(path).expect("Couldn't import map.")),
+            None => save::load(&args[0]).expect("Couldn't load world."),
+        },
+        _ => world::World::with_boundaries(args[0].parse().expect("Invalid map width."),
+                                           args[1].parse().expect("Invalid map height."), boundaries),
     };
+    if let Some(transport) = transport
+    {
+        world.parameters.transport = transport;
+    }
+    for field in fields
+    {
+        match world.map().field_index(&field.name)
+        {
+            Some(index) => world.set_field(index, field),
+            None => { world.add_field(field); },
+        }
+    }
+
+    let mut client = front_end::Client::new();
+    let mut world_renderer = front_end::WorldRenderer::new(&client.display, view(&world));
+    let window_size = client.window_size();
+    let mut scale = [window_size[0] / world_renderer.view().width as f32,
+                     window_size[1] / world_renderer.view().height as f32];
 
     let mut mouse_position: [isize; 2] = [0, 0];
     #[derive(PartialEq)]
@@ -52,27 +136,76 @@ fn main()
         EntitiesBrush,
         WallsBrush,
         DrainsBrush,
+        SourcesBrush,
+        EmittersBrush,
+        SinksBrush,
+        MaterialsBrush,
         PositivePressureBrush,
         NegativePressureBrush,
     }
+    enum Request
+    {
+        None,
+        Save(&'static str, save::Format),
+        Load(&'static str),
+        ImportMap,
+        Snapshot,
+    }
 
     let mut simulation_state = RunState::Running;
     let mut action = Action::None;
+    let mut request = Request::None;
+    let mut snapshot_number = 0u32;
+    let mut material = 1usize;
+    let mut speed = 1.0f32;
+    let mut accumulator = 0.0f32;
+    let mut last_frame = PreciseTime::now();
 
     while simulation_state != RunState::Exited
     {
-        if simulation_state != RunState::Paused
+        let now = PreciseTime::now();
+        let frame_time = last_frame.to(now).num_microseconds().unwrap() as f32 * 1e-6;
+        last_frame = now;
+        let steps = match simulation_state
         {
-            let start = PreciseTime::now();
-            world.simulate();
-            let end = PreciseTime::now();
-            println!("\tsimulation: {}us", start.to(end).num_microseconds().unwrap() as f32);
-            if simulation_state == RunState::Skipping
+            RunState::Running =>
+            {
+                accumulator += frame_time.min(MAX_FRAME_TIME) * speed;
+                let steps = (accumulator / time_step) as u32;
+                accumulator -= steps as f32 * time_step;
+                steps
+            },
+            RunState::Skipping =>
             {
                 simulation_state = RunState::Paused;
+                accumulator = 0.0;
+                1
+            },
+            _ => 0,
+        };
+        if steps > 0
+        {
+            let start = PreciseTime::now();
+            for _ in 0..steps
+            {
+                world.simulate(time_step);
             }
+            let end = PreciseTime::now();
+            println!("\tsimulation: {}us ({} steps)", start.to(end).num_microseconds().unwrap() as f32, steps);
         }
-        world_renderer.update(&world, &client.display);
+        // Steps taken while paused are shown whole, as there is no next step to blend towards.
+        let alpha = if simulation_state == RunState::Running { accumulator / time_step } else { 1.0 };
+        world_renderer.update(&world, world.previous_positions(), alpha, &client.display);
+        world.clear_dirty();
+        let matrix: [[f32; 3]; 3] =
+           [[scale[0], 0.0, 0.0],
+            [0.0, scale[1], 0.0],
+            [0.0, 0.0, 1.0]];
+        let uniforms = uniform!
+        {
+            matrix: matrix,
+            screen_size: window_size,
+        };
         client.clear_color([0.0, 0.0, 0.0, 1.0]);
         client.draw(&world_renderer.map_vertices, &world_renderer.map_indices, &uniforms);
         client.draw(&world_renderer.entities_vertices, &world_renderer.entities_indices, &uniforms);
@@ -85,8 +218,11 @@ fn main()
                 {
                     WindowEvent::Closed => simulation_state = RunState::Exited,
                     WindowEvent::MouseMoved{position, ..} =>
-                        mouse_position = [((position.0 + 0.5) / scale[0] as f64) as world::Coordinate,
-                                          ((position.1 + 0.5) / scale[1] as f64) as world::Coordinate],
+                    {
+                        let origin = world_renderer.view().origin;
+                        mouse_position = [origin[0] + ((position.0 + 0.5) / scale[0] as f64) as world::Coordinate,
+                                          origin[1] + ((position.1 + 0.5) / scale[1] as f64) as world::Coordinate];
+                    },
                     WindowEvent::KeyboardInput{input: KeyboardInput{virtual_keycode, state, ..}, ..} =>
                         match virtual_keycode
                         {
@@ -109,6 +245,50 @@ fn main()
                                 {
                                     action = Action::None;
                                 },
+                            Some(VirtualKeyCode::S) =>
+                                if state == ElementState::Pressed
+                                {
+                                    action = Action::SourcesBrush;
+                                }
+                                else if action == Action::SourcesBrush &&
+                                        state == ElementState::Released
+                                {
+                                    action = Action::None;
+                                },
+                            Some(VirtualKeyCode::A) =>
+                                if state == ElementState::Pressed
+                                {
+                                    action = Action::EmittersBrush;
+                                }
+                                else if action == Action::EmittersBrush &&
+                                        state == ElementState::Released
+                                {
+                                    action = Action::None;
+                                },
+                            Some(VirtualKeyCode::X) =>
+                                if state == ElementState::Pressed
+                                {
+                                    action = Action::SinksBrush;
+                                }
+                                else if action == Action::SinksBrush &&
+                                        state == ElementState::Released
+                                {
+                                    action = Action::None;
+                                },
+                            Some(VirtualKeyCode::M) =>
+                                if state == ElementState::Pressed
+                                {
+                                    action = Action::MaterialsBrush;
+                                }
+                                else if action == Action::MaterialsBrush &&
+                                        state == ElementState::Released
+                                {
+                                    action = Action::None;
+                                },
+                            Some(VirtualKeyCode::Key1) => material = 0,
+                            Some(VirtualKeyCode::Key2) => material = 1,
+                            Some(VirtualKeyCode::Key3) => material = 2,
+                            Some(VirtualKeyCode::Key4) => material = 3,
                             Some(VirtualKeyCode::Q) =>
                                 if state == ElementState::Pressed
                                 {
@@ -121,6 +301,22 @@ fn main()
                                         simulation_state = RunState::Paused;
                                     }
                                 },
+                            Some(VirtualKeyCode::Tab) =>
+                                if state == ElementState::Pressed
+                                {
+                                    let field = (world_renderer.field() + 1) % world.map().fields().len();
+                                    world_renderer.show_field(field);
+                                    println!("showing field: {}", world.map().fields()[field].name);
+                                },
+                            Some(VirtualKeyCode::B) =>
+                                if state == ElementState::Pressed
+                                {
+                                    for (field, budget) in world.map().fields().iter().zip(world.budgets().iter())
+                                    {
+                                        println!("{}: total {} drained {} brushed {} residual {}", field.name,
+                                                 budget.total, budget.drained(), budget.brushed, budget.residual());
+                                    }
+                                },
                             Some(VirtualKeyCode::Comma) =>
                                 if state == ElementState::Pressed
                                 {
@@ -129,6 +325,75 @@ fn main()
                                         simulation_state = RunState::Skipping;
                                     }
                                 },
+                            Some(VirtualKeyCode::C) =>
+                                if state == ElementState::Pressed
+                                {
+                                    world.parameters.entity_coupling =
+                                        if world.parameters.entity_coupling == 0.0 { ENTITY_COUPLING } else { 0.0 };
+                                    println!("entity coupling: {}", world.parameters.entity_coupling);
+                                },
+                            Some(VirtualKeyCode::LBracket) =>
+                                if state == ElementState::Pressed
+                                {
+                                    speed = (speed / 2.0).max(MIN_SPEED);
+                                    println!("speed: {}x", speed);
+                                },
+                            Some(VirtualKeyCode::RBracket) =>
+                                if state == ElementState::Pressed
+                                {
+                                    speed = (speed * 2.0).min(MAX_SPEED);
+                                    println!("speed: {}x", speed);
+                                },
+                            Some(VirtualKeyCode::F5) =>
+                                if state == ElementState::Pressed
+                                {
+                                    request = Request::Save(QUICKSAVE_BINARY, save::Format::Binary);
+                                },
+                            Some(VirtualKeyCode::F6) =>
+                                if state == ElementState::Pressed
+                                {
+                                    request = Request::Save(QUICKSAVE_TEXT, save::Format::Text);
+                                },
+                            Some(VirtualKeyCode::F9) =>
+                                if state == ElementState::Pressed
+                                {
+                                    request = Request::Load(QUICKSAVE_BINARY);
+                                },
+                            Some(VirtualKeyCode::F10) =>
+                                if state == ElementState::Pressed
+                                {
+                                    request = Request::Load(QUICKSAVE_TEXT);
+                                },
+                            Some(VirtualKeyCode::F8) =>
+                                if state == ElementState::Pressed
+                                {
+                                    request = Request::ImportMap;
+                                },
+                            Some(VirtualKeyCode::F12) =>
+                                if state == ElementState::Pressed
+                                {
+                                    request = Request::Snapshot;
+                                },
+                            Some(VirtualKeyCode::Left) =>
+                                if state == ElementState::Pressed
+                                {
+                                    world_renderer.pan([-PAN_STEP, 0]);
+                                },
+                            Some(VirtualKeyCode::Right) =>
+                                if state == ElementState::Pressed
+                                {
+                                    world_renderer.pan([PAN_STEP, 0]);
+                                },
+                            Some(VirtualKeyCode::Up) =>
+                                if state == ElementState::Pressed
+                                {
+                                    world_renderer.pan([0, -PAN_STEP]);
+                                },
+                            Some(VirtualKeyCode::Down) =>
+                                if state == ElementState::Pressed
+                                {
+                                    world_renderer.pan([0, PAN_STEP]);
+                                },
                             Some(VirtualKeyCode::Escape) =>
                                 simulation_state = RunState::Exited,
                             _ => (),
@@ -173,14 +438,101 @@ fn main()
                 _ => (),
             }
         });
+        let loaded = match std::mem::replace(&mut request, Request::None)
+        {
+            Request::Save(path, format) =>
+            {
+                match save::save(&world, path, format)
+                {
+                    Ok(()) => println!("saved world to {}", path),
+                    Err(error) => eprintln!("couldn't save world to {}: {}", path, error),
+                }
+                None
+            },
+            Request::Load(path) => match save::load(path)
+            {
+                Ok(loaded) =>
+                {
+                    println!("loaded world from {}", path);
+                    Some(loaded)
+                },
+                Err(error) =>
+                {
+                    eprintln!("couldn't load world from {}: {}", path, error);
+                    None
+                },
+            },
+            Request::ImportMap => match map_image
+            {
+                Some(ref path) => match image::load_map(path)
+                {
+                    Ok(map) =>
+                    {
+                        println!("imported map from {}", path);
+                        Some(world::World::from_map(map))
+                    },
+                    Err(error) =>
+                    {
+                        eprintln!("couldn't import map from {}: {}", path, error);
+                        None
+                    },
+                },
+                None =>
+                {
+                    eprintln!("no map image was given on the command line");
+                    None
+                },
+            },
+            Request::Snapshot =>
+            {
+                let path = format!("snapshot-{:04}.png", snapshot_number);
+                snapshot_number += 1;
+                match image::save_snapshot(&world, world_renderer.field(), &path, SNAPSHOT_SCALE)
+                {
+                    Ok(()) => println!("saved snapshot to {}", path),
+                    Err(error) => eprintln!("couldn't save snapshot to {}: {}", path, error),
+                }
+                None
+            },
+            Request::None => None,
+        };
+        if let Some(loaded) = loaded
+        {
+            world = loaded;
+            world_renderer = front_end::WorldRenderer::new(&client.display, view(&world));
+            scale = [window_size[0] / world_renderer.view().width as f32,
+                     window_size[1] / world_renderer.view().height as f32];
+        }
         match action
         {
             Action::EntitiesBrush => world.place_entity(mouse_position),
             Action::WallsBrush => world.brush(world::Tile::Wall, mouse_position, 1.0),
             Action::DrainsBrush => world.brush(world::Tile::Drain, mouse_position, 1.0),
-            Action::PositivePressureBrush => world.brush(world::Tile::Empty(1.0), mouse_position, 3.0),
-            Action::NegativePressureBrush => world.brush(world::Tile::Empty(-1.0), mouse_position, 3.0),
+            Action::SourcesBrush => world.brush(world::Tile::Source(SOURCE_VALUE), mouse_position, 1.0),
+            Action::EmittersBrush => world.brush(world::Tile::Emitter(FIXTURE_RATE), mouse_position, 1.0),
+            Action::SinksBrush => world.brush(world::Tile::Sink(FIXTURE_RATE), mouse_position, 1.0),
+            Action::MaterialsBrush =>
+                if let Err(error) = world.paint_material(MATERIALS[material], mouse_position, 3.0)
+                {
+                    eprintln!("couldn't paint material: {}", error);
+                },
+            // Values are painted into the field that is shown.
+            Action::PositivePressureBrush =>
+                world.brush_field(world_renderer.field(), world::Tile::Empty(1.0), mouse_position, 3.0),
+            Action::NegativePressureBrush =>
+                world.brush_field(world_renderer.field(), world::Tile::Empty(-1.0), mouse_position, 3.0),
             _ => (),
         }
     }
 }
+
+/// The tiles shown at first: the whole of a bounded map, or a default sized window at the
+/// origin of an unbounded one.
+fn view(world: &world::World) -> world::Bounds
+{
+    match world.map().extent()
+    {
+        Some(extent) => extent,
+        None => world::Bounds::new([0, 0], DEFAULT_WIDTH, DEFAULT_HEIGHT),
+    }
+}
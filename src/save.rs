@@ -0,0 +1,225 @@
+use std;
+use std::io::Read;
+use std::io::Write;
+
+use bincode;
+use serde_json;
+
+use world;
+
+/// Bumped whenever the serialized layout of `World` changes. Saves of other versions are
+/// rejected rather than migrated.
+pub const FORMAT_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"PHRS";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Format
+{
+    Binary,
+    Text,
+}
+
+impl Format
+{
+    /// Picks the text format for `.json` files and the binary one otherwise.
+    pub fn from_path<P: AsRef<std::path::Path>>(path: P) -> Self
+    {
+        match path.as_ref().extension().and_then(|extension| extension.to_str())
+        {
+            Some("json") => Format::Text,
+            _ => Format::Binary,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+    Io(std::io::Error),
+    Binary(bincode::Error),
+    Text(serde_json::Error),
+    UnsupportedVersion(u32),
+    Corrupted,
+}
+
+impl std::fmt::Display for Error
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match *self
+        {
+            Error::Io(ref error) => write!(f, "I/O error: {}", error),
+            Error::Binary(ref error) => write!(f, "invalid binary save: {}", error),
+            Error::Text(ref error) => write!(f, "invalid text save: {}", error),
+            Error::UnsupportedVersion(version) =>
+                write!(f, "unsupported save version {} (expected {})", version, FORMAT_VERSION),
+            Error::Corrupted => write!(f, "save file is inconsistent"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error
+{
+    fn from(error: std::io::Error) -> Self
+    {
+        Error::Io(error)
+    }
+}
+
+impl From<bincode::Error> for Error
+{
+    fn from(error: bincode::Error) -> Self
+    {
+        Error::Binary(error)
+    }
+}
+
+impl From<serde_json::Error> for Error
+{
+    fn from(error: serde_json::Error) -> Self
+    {
+        Error::Text(error)
+    }
+}
+
+#[derive(Serialize)]
+struct TextSave<'a>
+{
+    version: u32,
+    world: &'a world::World,
+}
+
+pub fn save<P: AsRef<std::path::Path>>(world: &world::World, path: P, format: Format)
+    -> Result<(), Error>
+{
+    let file = std::fs::File::create(path)?;
+    let mut writer = std::io::BufWriter::new(file);
+    match format
+    {
+        Format::Binary =>
+        {
+            writer.write_all(MAGIC)?;
+            bincode::serialize_into(&mut writer, &FORMAT_VERSION)?;
+            bincode::serialize_into(&mut writer, world)?;
+        },
+        Format::Text =>
+        {
+            serde_json::to_writer_pretty(&mut writer, &TextSave{version: FORMAT_VERSION, world})?;
+        },
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Loads a world saved in either format, telling them apart by the binary magic.
+pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<world::World, Error>
+{
+    let mut data = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut data)?;
+    let world: world::World = if data.starts_with(MAGIC)
+    {
+        let mut reader = &data[MAGIC.len()..];
+        let version: u32 = bincode::deserialize_from(&mut reader)?;
+        if version != FORMAT_VERSION
+        {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        bincode::deserialize_from(&mut reader)?
+    }
+    else
+    {
+        let mut value: serde_json::Value = serde_json::from_slice(&data)?;
+        let version = match value.get("version").and_then(|version| version.as_u64())
+        {
+            Some(version) => version as u32,
+            None => return Err(Error::Corrupted),
+        };
+        if version != FORMAT_VERSION
+        {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        serde_json::from_value(value["world"].take())?
+    };
+    if world.is_consistent()
+    {
+        Ok(world)
+    }
+    else
+    {
+        Err(Error::Corrupted)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn round_trip(format: Format, extension: &str) -> ()
+    {
+        let boundaries = [world::Boundary::Periodic, world::Boundary::Fixed(0.5)];
+        let mut world = world::World::with_boundaries(40, 30, boundaries);
+        world.add_field(world::Field::new("dye"));
+        world.brush(world::Tile::Empty(1.0), [10, 10], 4.0);
+        world.brush(world::Tile::Wall, [25, 15], 2.0);
+        world.brush(world::Tile::Drain, [35, 5], 1.0);
+        world.brush_field(1, world::Tile::Empty(0.75), [20, 20], 3.0);
+        let material = world::Material{diffusivity: 0.5, permeability: 0.25, drag: 2.0};
+        world.paint_material(material, [5, 25], 3.0).unwrap();
+        world.place_entity([12, 12]);
+        world.place_entity([30, 20]);
+        for _ in 0..10
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        let path = std::env::temp_dir().join(format!("physics-rs-round-trip-{}.{}", std::process::id(), extension));
+        save(&world, &path, format).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bincode::serialize(&loaded).unwrap(), bincode::serialize(&world).unwrap());
+        assert_eq!(loaded.map().totals(), world.map().totals());
+    }
+
+    #[test]
+    fn binary_saves_round_trip() -> ()
+    {
+        round_trip(Format::Binary, "bin");
+    }
+
+    #[test]
+    fn text_saves_round_trip() -> ()
+    {
+        round_trip(Format::Text, "json");
+    }
+
+    #[test]
+    fn loaded_worlds_carry_on_as_saved_ones() -> ()
+    {
+        let mut world = world::World::new(256, 192);
+        world.parameters.transport = world::Transport::Diffusion;
+        world.brush(world::Tile::Empty(1.0), [40, 40], 10.0);
+        for _ in 0..50
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        let path = std::env::temp_dir().join(format!("physics-rs-carry-on-{}.bin", std::process::id()));
+        save(&world, &path, Format::Binary).unwrap();
+        let mut loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let chunks: Vec<world::ChunkPosition> = (0..12).map(|i| [i % 4, i / 4]).collect();
+        assert!(chunks.iter().any(|&chunk| !world.map().activity().is_active(chunk)));
+        for _ in 0..50
+        {
+            for &chunk in chunks.iter()
+            {
+                assert_eq!(loaded.map().activity().is_active(chunk), world.map().activity().is_active(chunk));
+            }
+            world.simulate(1.0 / 60.0);
+            loaded.simulate(1.0 / 60.0);
+        }
+        let bounds = world.bounds();
+        assert!(loaded.map().tiles(bounds) == world.map().tiles(bounds));
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+use world;
+use entity;
+
+/// Buckets entity indices into square cells so nearby entities can be found without
+/// comparing every pair.
+pub struct SpatialHash
+{
+    cell_size: f32,
+    cells: HashMap<world::Point, Vec<usize>>,
+}
+
+impl SpatialHash
+{
+    /// `cell_size` is in tiles and should be at least the largest interaction distance, so
+    /// that only the surrounding 3x3 cells need to be searched.
+    pub fn new(cell_size: f32) -> Self
+    {
+        Self
+        {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    pub fn cell_of(&self, position: entity::Point) -> world::Point
+    {
+        [(position[0] / self.cell_size).floor() as world::Coordinate,
+         (position[1] / self.cell_size).floor() as world::Coordinate]
+    }
+
+    pub fn insert(&mut self, index: usize, position: entity::Point) -> ()
+    {
+        let cell = self.cell_of(position);
+        self.cells.entry(cell).or_default().push(index);
+    }
+
+    /// Indices of all entities in the cells around `position`, in insertion order per cell.
+    pub fn neighbours(&self, position: entity::Point) -> Vec<usize>
+    {
+        let cell = self.cell_of(position);
+        let mut result = Vec::new();
+        for y in (cell[1] - 1)..(cell[1] + 2)
+        {
+            for x in (cell[0] - 1)..(cell[0] + 2)
+            {
+                if let Some(indices) = self.cells.get(&[x, y])
+                {
+                    result.extend_from_slice(indices);
+                }
+            }
+        }
+        result
+    }
+}
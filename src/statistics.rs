@@ -0,0 +1,82 @@
+use world;
+
+pub struct Statistics
+{
+    pub total_value: f32,
+    pub min_value: f32,
+    pub max_value: f32,
+    pub mean_value: f32,
+    pub entities: usize,
+    pub mean_speed: f32,
+    /// Pressure removed by drains in the last step.
+    pub drained: f64,
+    /// Pressure added by brushes before the last step.
+    pub brushed: f64,
+    /// Change of the pressure in the last step that isn't accounted for.
+    pub residual: f64,
+}
+
+impl Statistics
+{
+    pub fn new(world: &world::World) -> Self
+    {
+        let mut total_value = 0.0f32;
+        let mut min_value = f32::INFINITY;
+        let mut max_value = f32::NEG_INFINITY;
+        let mut count = 0u32;
+        // Only the islands of an unbounded map are read, as the rest of it is blank.
+        for island in world.map().islands()
+        {
+            for tile in world.map().tiles(island)
+            {
+                if let world::Tile::Empty(value) = tile
+                {
+                    total_value += value;
+                    min_value = min_value.min(value);
+                    max_value = max_value.max(value);
+                    count += 1;
+                }
+            }
+        }
+        if count == 0
+        {
+            min_value = 0.0;
+            max_value = 0.0;
+        }
+        let mean_value = if count == 0 { 0.0 } else { total_value / count as f32 };
+
+        let entities = world.entities.0.len();
+        let mut total_speed = 0.0f32;
+        for entity in world.entities.0.iter()
+        {
+            total_speed += (entity.velocity[0].powi(2) + entity.velocity[1].powi(2)).sqrt();
+        }
+        let mean_speed = if entities == 0 { 0.0 } else { total_speed / entities as f32 };
+
+        let budget = world.budgets().first().cloned().unwrap_or_default();
+
+        Self
+        {
+            total_value,
+            min_value,
+            max_value,
+            mean_value,
+            entities,
+            mean_speed,
+            drained: budget.drained(),
+            brushed: budget.brushed,
+            residual: budget.residual(),
+        }
+    }
+
+    pub fn csv_header() -> &'static str
+    {
+        "total_value,min_value,max_value,mean_value,entities,mean_speed,drained,brushed,residual"
+    }
+
+    pub fn to_csv(&self) -> String
+    {
+        format!("{},{},{},{},{},{},{},{},{}", self.total_value, self.min_value, self.max_value,
+                self.mean_value, self.entities, self.mean_speed, self.drained, self.brushed, self.residual)
+    }
+}
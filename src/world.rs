@@ -1,189 +1,896 @@
 use std;
+use std::collections::HashMap;
+
+use serde;
 
 use entity;
+use fluid;
+use field;
+use activity;
+use budget;
+
+mod boundary;
+mod chunk;
+mod diffusion;
+mod material;
+
+pub use self::boundary::Boundary;
+pub use self::chunk::{CHUNK_SIZE, ChunkPosition, chunk_of, chunk_bounds};
+pub use self::material::{Material, MaterialIndex};
 
-pub const WIDTH: usize = 300;
-pub const HEIGHT: usize = 200;
+use self::chunk::{Chunk, Kind, tile_index, runs};
 
 type TileValue = f32;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum Tile
 {
     Empty(TileValue),
     Wall,
+    /// Holds its surroundings at zero, taking away whatever diffuses or flows into it.
     Drain,
+    /// Holds its surroundings at a fixed value, like a drain at that value instead of zero.
+    Source(TileValue),
+    /// Adds a rate of value per second to the empty tiles around it.
+    Emitter(f32),
+    /// Takes up to a rate of value per second from the empty tiles around it, bringing them
+    /// towards zero.
+    Sink(f32),
+}
+
+impl Tile
+{
+    /// What the tile adds to the quantity of its field: the value of an empty tile, and nothing
+    /// for reservoirs and closed tiles.
+    pub fn quantity(&self) -> TileValue
+    {
+        match self
+        {
+            &Tile::Empty(value) => value,
+            _ => 0.0,
+        }
+    }
 }
 
-type Index = [usize; 2];
 pub type Coordinate = isize;
 pub type Point = [Coordinate; 2];
 
-pub struct Map([[Tile; WIDTH]; HEIGHT]);
+/// Position of a field among those of a map.
+pub type FieldIndex = usize;
 
-impl Map
+/// A scalar quantity every tile holds a value of, like pressure, temperature or the
+/// concentration of a dye. Field 0 of a map is the pressure, which drives the flow; the others
+/// are only carried along.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Field
 {
-    pub fn new() -> Self
+    pub name: String,
+    /// Scale of the diffusion rate of the field, relative to `Parameters::diffusion_rate`.
+    pub diffusivity: f32,
+    /// What the field finds past the open and fixed-value edges of the map along x and y: a
+    /// fixed value, or the value of the edge tile for any other boundary. Walled and periodic
+    /// edges of the map are the same for every field.
+    pub boundaries: [Boundary; 2],
+    /// Weight of the gradient of the field in the force on entities.
+    pub force_weight: f32,
+}
+
+impl Field
+{
+    /// A field diffusing at the base rate, with no value diffusing across the edges of the map
+    /// and no force on entities.
+    pub fn new(name: &str) -> Self
     {
-        let mut tiles = [[Tile::Empty(0.0); WIDTH]; HEIGHT];
-        for x in 0..WIDTH
+        Self
         {
-            tiles[0][x] = Tile::Wall;
-            tiles[HEIGHT - 1][x] = Tile::Wall;
+            name: name.to_string(),
+            diffusivity: 1.0,
+            boundaries: [Boundary::Open; 2],
+            force_weight: 0.0,
         }
-        for y in 0..HEIGHT
+    }
+
+    /// The first field of every map, pushing entities down its gradient.
+    fn pressure(boundaries: [Boundary; 2]) -> Self
+    {
+        Self
         {
-            tiles[y][0] = Tile::Wall;
-            tiles[y][WIDTH - 1] = Tile::Wall;
+            boundaries,
+            force_weight: 1.0,
+            ..Self::new("pressure")
         }
-        Self{0: tiles}
     }
+}
+
+impl std::str::FromStr for Field
+{
+    type Err = String;
 
-    pub fn at(&self, point: Point) -> Option<&Tile>
+    /// Parses `NAME,DIFFUSIVITY,FORCE_WEIGHT`, optionally followed by the boundaries along x
+    /// and y as parsed by `Boundary`.
+    fn from_str(description: &str) -> Result<Self, Self::Err>
     {
-        match Self::to_index(point)
+        let parts: Vec<&str> = description.split(',').map(|part| part.trim()).collect();
+        if (parts.len() != 3 && parts.len() != 5) || parts[0].is_empty()
+        {
+            return Err(format!("invalid field {}", description));
+        }
+        let mut field = Field::new(parts[0]);
+        field.diffusivity = parts[1].parse().map_err(|_| format!("invalid diffusivity in {}", description))?;
+        field.force_weight = parts[2].parse().map_err(|_| format!("invalid force weight in {}", description))?;
+        if field.diffusivity < 0.0 || field.diffusivity.is_nan()
         {
-            Some(index) => Some(&self.0[index[1]][index[0]]),
-            None => None,
+            return Err(format!("negative diffusivity in {}", description));
         }
+        if parts.len() == 5
+        {
+            field.boundaries = [parts[3].parse()?, parts[4].parse()?];
+        }
+        Ok(field)
     }
+}
+
+/// What carries the values of the fields from tile to tile.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Transport
+{
+    /// The flow advects the fields, which diffuse at `Parameters::diffusion_rate` as well.
+    Fluid,
+    /// There is no flow, and the fields only diffuse at `Parameters::diffusion_rate`.
+    Diffusion,
+}
 
-    pub fn at_mut(&mut self, point: Point) -> Option<&mut Tile>
+impl std::str::FromStr for Transport
+{
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err>
     {
-        match Self::to_index(point)
+        match name
         {
-            Some(index) => Some(&mut self.0[index[1]][index[0]]),
-            None => None,
+            "fluid" => Ok(Transport::Fluid),
+            "diffusion" => Ok(Transport::Diffusion),
+            _ => Err(format!("unknown transport {}", name)),
         }
     }
+}
 
-    pub fn simulate(&mut self) -> ()
+/// Physical constants of a world. Tile coordinates are converted to metres with `cell_size`,
+/// and every rate is per second so results don't depend on the time step.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Parameters
+{
+    /// Edge length of a tile, in metres.
+    pub cell_size: f32,
+    pub transport: Transport,
+    /// Diffusion coefficient of tile values, in square metres per second.
+    pub diffusion_rate: f32,
+    /// Acceleration of entities per unit of the gradient of the fields, weighted by their force
+    /// weights, in square metres per second squared.
+    pub pressure_response: f32,
+    /// How fields are interpolated between tile centres for entity forces.
+    pub interpolation: field::Interpolation,
+    /// How the gradients driving entities are estimated.
+    pub gradient_operator: field::GradientOperator,
+    /// Exponential decay rate of entity velocity, per second.
+    pub damping: f32,
+    /// Rate at which entities match the fluid velocity, per second.
+    pub fluid_drag: f32,
+    /// Fraction of the approach speed kept when two entities collide, from `0.0` for fully
+    /// inelastic to `1.0` for elastic collisions.
+    pub entity_restitution: f32,
+    /// Fraction of the normal speed kept when an entity bounces off a wall.
+    pub wall_restitution: f32,
+    /// Coulomb friction coefficient between entities and walls.
+    pub wall_friction: f32,
+    /// Pressure moved by an entity per kilogram and metre travelled, pushing it along with the
+    /// entity. Zero leaves the pressure unaffected by entities.
+    pub entity_coupling: f32,
+}
+
+impl Default for Parameters
+{
+    fn default() -> Self
     {
-        unsafe
+        Self
+        {
+            cell_size: 0.01,
+            transport: Transport::Fluid,
+            diffusion_rate: 0.002,
+            pressure_response: 0.36,
+            interpolation: field::Interpolation::Bilinear,
+            gradient_operator: field::GradientOperator::Scharr,
+            damping: 0.6,
+            fluid_drag: 3.0,
+            entity_restitution: 0.9,
+            wall_restitution: 0.8,
+            wall_friction: 0.1,
+            entity_coupling: 0.0,
+        }
+    }
+}
+
+/// A rectangle of tiles.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Bounds
+{
+    pub origin: Point,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Bounds
+{
+    pub fn new(origin: Point, width: usize, height: usize) -> Self
+    {
+        Self
+        {
+            origin,
+            width,
+            height,
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.width * self.height
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, point: Point) -> bool
+    {
+        point[0] >= self.origin[0] && point[1] >= self.origin[1] &&
+        point[0] < self.origin[0] + self.width as Coordinate &&
+        point[1] < self.origin[1] + self.height as Coordinate
+    }
+
+    /// Row-major index of `point` within the rectangle.
+    pub fn index(&self, point: Point) -> Option<usize>
+    {
+        if self.contains(point)
+        {
+            Some(((point[1] - self.origin[1]) as usize * self.width) + (point[0] - self.origin[0]) as usize)
+        }
+        else
         {
-            let mut buffer: [[Tile; WIDTH]; HEIGHT] = std::mem::uninitialized();
-            for y in 0..HEIGHT
+            None
+        }
+    }
+
+    /// Moves `point` into the rectangle along the axes where `boundaries` are periodic.
+    pub fn wrap(&self, point: Point, boundaries: [Boundary; 2]) -> Point
+    {
+        let size = [self.width as Coordinate, self.height as Coordinate];
+        let mut wrapped = point;
+        for axis in 0..2
+        {
+            if boundaries[axis] == Boundary::Periodic
             {
-                for x in 0..WIDTH
+                wrapped[axis] = self.origin[axis] + (point[axis] - self.origin[axis]).rem_euclid(size[axis]);
+            }
+        }
+        wrapped
+    }
+
+    /// The point with row-major `index` within the rectangle.
+    pub fn point(&self, index: usize) -> Point
+    {
+        [self.origin[0] + (index % self.width) as Coordinate,
+         self.origin[1] + (index / self.width) as Coordinate]
+    }
+
+    pub fn intersection(&self, other: &Bounds) -> Option<Bounds>
+    {
+        let start = [self.origin[0].max(other.origin[0]), self.origin[1].max(other.origin[1])];
+        let end = [(self.origin[0] + self.width as Coordinate).min(other.origin[0] + other.width as Coordinate),
+                   (self.origin[1] + self.height as Coordinate).min(other.origin[1] + other.height as Coordinate)];
+        if start[0] < end[0] && start[1] < end[1]
+        {
+            Some(Bounds::new(start, (end[0] - start[0]) as usize, (end[1] - start[1]) as usize))
+        }
+        else
+        {
+            None
+        }
+    }
+}
+
+/// Mutable view of a tile of a `Map` through one of its fields, stored back into it when
+/// dropped.
+pub struct TileMut<'a>
+{
+    tile: Tile,
+    field: FieldIndex,
+    index: usize,
+    chunk: &'a mut Chunk,
+}
+
+impl<'a> std::ops::Deref for TileMut<'a>
+{
+    type Target = Tile;
+
+    fn deref(&self) -> &Tile
+    {
+        &self.tile
+    }
+}
+
+impl<'a> std::ops::DerefMut for TileMut<'a>
+{
+    fn deref_mut(&mut self) -> &mut Tile
+    {
+        &mut self.tile
+    }
+}
+
+impl<'a> Drop for TileMut<'a>
+{
+    fn drop(&mut self) -> ()
+    {
+        self.chunk.set(self.field, self.index, self.tile);
+    }
+}
+
+/// A map is stored in chunks that are allocated as they are written to and unloaded once they
+/// are blank and have been idle for a while; tiles of chunks that aren't loaded read as
+/// `Tile::Empty(0.0)`. A map either has a fixed extent, outside of which there are no tiles and
+/// its boundaries apply, or is unbounded and grows wherever it is painted or values diffuse to.
+///
+/// Every tile holds a value of each of the fields of the map. Methods that don't take a field
+/// see the tiles through the pressure, field 0.
+#[derive(Serialize, Deserialize)]
+pub struct Map
+{
+    extent: Option<Bounds>,
+    /// Boundaries of the extent along x and y.
+    boundaries: [Boundary; 2],
+    /// Fields held by every tile, starting with the pressure.
+    fields: Vec<Field>,
+    /// Palette of the materials tiles refer to, starting with the default one.
+    materials: Vec<Material>,
+    #[serde(serialize_with = "serialize_chunks", deserialize_with = "deserialize_chunks")]
+    chunks: HashMap<ChunkPosition, Chunk>,
+    activity: activity::Activity,
+}
+
+/// Chunks are saved as a list ordered by position, as JSON only has string keys and the order
+/// of a `HashMap` varies between runs.
+fn serialize_chunks<S: serde::Serializer>(chunks: &HashMap<ChunkPosition, Chunk>, serializer: S)
+    -> Result<S::Ok, S::Error>
+{
+    let mut sorted: Vec<(&ChunkPosition, &Chunk)> = chunks.iter().collect();
+    sorted.sort_by_key(|&(position, _)| [position[1], position[0]]);
+    serializer.collect_seq(sorted)
+}
+
+fn deserialize_chunks<'de, D: serde::Deserializer<'de>>(deserializer: D)
+    -> Result<HashMap<ChunkPosition, Chunk>, D::Error>
+{
+    let chunks: Vec<(ChunkPosition, Chunk)> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(chunks.into_iter().collect())
+}
+
+impl Map
+{
+    /// A `width` x `height` map surrounded by walls.
+    pub fn new(width: usize, height: usize) -> Self
+    {
+        Self::with_boundaries(width, height, [Boundary::Walled; 2])
+    }
+
+    /// A `width` x `height` map with the given boundaries along x and y, which the pressure
+    /// takes as its own.
+    pub fn with_boundaries(width: usize, height: usize, boundaries: [Boundary; 2]) -> Self
+    {
+        assert!(width > 0 && height > 0, "Map dimensions must be non-zero.");
+        let mut map = Self
+        {
+            extent: Some(Bounds::new([0, 0], width, height)),
+            boundaries,
+            fields: vec![Field::pressure(boundaries)],
+            materials: vec![Material::default()],
+            chunks: HashMap::new(),
+            activity: activity::Activity::default(),
+        };
+        for y in 0..height
+        {
+            for x in 0..width
+            {
+                let on_edge = [x == 0 || x == width - 1, y == 0 || y == height - 1];
+                let point = [x as Coordinate, y as Coordinate];
+                if (0..2).any(|axis| on_edge[axis] && boundaries[axis] == Boundary::Walled)
                 {
-                    let position = [x as Coordinate, y as Coordinate];
-                    buffer[y][x] = self.average_tile(position).unwrap();
+                    *map.at_mut(point).unwrap() = Tile::Wall;
                 }
             }
-            std::mem::swap(&mut self.0, &mut buffer);
         }
+        map.touch_fixed_edges();
+        map.activity = activity::Activity::new(map.chunks.keys().cloned());
+        map
+    }
+
+    pub fn unbounded() -> Self
+    {
+        Self
+        {
+            extent: None,
+            boundaries: [Boundary::Walled; 2],
+            fields: vec![Field::pressure([Boundary::Walled; 2])],
+            materials: vec![Material::default()],
+            chunks: HashMap::new(),
+            activity: activity::Activity::new(std::iter::empty()),
+        }
+    }
+
+    pub fn extent(&self) -> Option<Bounds>
+    {
+        self.extent
+    }
+
+    /// Boundaries along x and y, which only apply to bounded maps.
+    pub fn boundaries(&self) -> [Boundary; 2]
+    {
+        self.boundaries
     }
 
-    fn average_tile(&self, position: Point) -> Option<Tile>
+    /// The extent of a bounded map, or the smallest rectangle covering every loaded chunk.
+    pub fn bounds(&self) -> Bounds
     {
-        match self.at(position)
+        if let Some(extent) = self.extent
+        {
+            return extent;
+        }
+        let mut chunks = self.chunks.keys();
+        let first = match chunks.next()
+        {
+            Some(&first) => first,
+            None => return Bounds::new([0, 0], 0, 0),
+        };
+        let (mut start, mut end) = (first, first);
+        for chunk in chunks
         {
-            Some(tile) => match tile
+            start = [start[0].min(chunk[0]), start[1].min(chunk[1])];
+            end = [end[0].max(chunk[0]), end[1].max(chunk[1])];
+        }
+        Bounds::new([start[0] * CHUNK_SIZE as Coordinate, start[1] * CHUNK_SIZE as Coordinate],
+                    (end[0] - start[0] + 1) as usize * CHUNK_SIZE,
+                    (end[1] - start[1] + 1) as usize * CHUNK_SIZE)
+    }
+
+    /// Checks that the chunk storage is intact, e.g. after deserializing.
+    pub fn is_consistent(&self) -> bool
+    {
+        self.extent.is_none_or(|extent| !extent.is_empty()) && !self.fields.is_empty() &&
+        !self.materials.is_empty() && self.materials.len() <= MaterialIndex::MAX as usize + 1 &&
+        self.materials.iter().all(|material| material.validate().is_ok()) &&
+        self.chunks.values().all(|chunk| chunk.is_consistent(self.fields.len(), self.materials.len()))
+    }
+
+    pub fn contains(&self, point: Point) -> bool
+    {
+        self.extent.is_none_or(|extent| extent.contains(point))
+    }
+
+    pub fn at(&self, point: Point) -> Option<Tile>
+    {
+        self.field_at(0, point)
+    }
+
+    /// The tile at `point`, holding its value of `field`.
+    pub fn field_at(&self, field: FieldIndex, point: Point) -> Option<Tile>
+    {
+        if !self.contains(point)
+        {
+            return None;
+        }
+        match self.chunks.get(&chunk_of(point))
+        {
+            Some(chunk) => Some(chunk.tile(field, tile_index(point))),
+            None => Some(Tile::Empty(0.0)),
+        }
+    }
+
+    pub fn fields(&self) -> &[Field]
+    {
+        &self.fields
+    }
+
+    pub fn field_index(&self, name: &str) -> Option<FieldIndex>
+    {
+        self.fields.iter().position(|field| field.name == name)
+    }
+
+    /// Adds a field holding zero everywhere, whose name mustn't be taken yet.
+    pub fn add_field(&mut self, field: Field) -> FieldIndex
+    {
+        assert!(self.field_index(&field.name).is_none(), "Field name is taken.");
+        self.fields.push(field);
+        for chunk in self.chunks.values_mut()
+        {
+            chunk.values.push(vec![0.0; CHUNK_SIZE * CHUNK_SIZE]);
+        }
+        self.touch_fixed_edges();
+        self.fields.len() - 1
+    }
+
+    /// Replaces the description of the field at `index`, keeping its values.
+    pub fn set_field(&mut self, index: FieldIndex, field: Field) -> ()
+    {
+        assert!(self.fields.iter().enumerate().all(|(i, other)| i == index || other.name != field.name),
+                "Field name is taken.");
+        self.fields[index] = field;
+        self.touch_fixed_edges();
+    }
+
+    /// Loads the chunk of the tile if needed, and flags the tile as changed, so that it is
+    /// simulated and redrawn.
+    pub fn at_mut<'a>(&'a mut self, point: Point) -> Option<TileMut<'a>>
+    {
+        self.field_at_mut(0, point)
+    }
+
+    /// The tile at `point` holding its value of `field`, as with `at_mut`. Changing its kind
+    /// resets its values of the other fields.
+    pub fn field_at_mut<'a>(&'a mut self, field: FieldIndex, point: Point) -> Option<TileMut<'a>>
+    {
+        if !self.contains(point)
+        {
+            return None;
+        }
+        let position = chunk_of(point);
+        self.activity.mark(position);
+        let chunk = self.load(position);
+        let index = tile_index(point);
+        Some(TileMut
+        {
+            tile: chunk.tile(field, index),
+            field,
+            index,
+            chunk,
+        })
+    }
+
+    /// Loads the chunk containing `point`, if it is within the map, and keeps it loaded for a
+    /// while.
+    pub fn touch(&mut self, point: Point) -> ()
+    {
+        if self.contains(point)
+        {
+            self.load(chunk_of(point));
+        }
+    }
+
+    /// Reads the tiles within `bounds` in row-major order. Tiles outside the map read as walls.
+    pub fn tiles(&self, bounds: Bounds) -> Vec<Tile>
+    {
+        self.field_tiles(0, bounds)
+    }
+
+    /// Reads the tiles within `bounds` holding their values of `field`, as with `tiles`.
+    pub fn field_tiles(&self, field: FieldIndex, bounds: Bounds) -> Vec<Tile>
+    {
+        let mut tiles = Vec::with_capacity(bounds.len());
+        for (point, run) in runs(bounds)
+        {
+            let index = tile_index(point);
+            let chunk = self.chunks.get(&chunk_of(point));
+            for i in 0..run
             {
-                &Tile::Empty(_) =>
+                tiles.push(match chunk
                 {
-                    let mut sum = 0.0f32;
-                    let mut count = 0u32;
-                    for y in (position[1] - 1)..(position[1] + 2)
-                    {
-                        for x in (position[0] - 1)..(position[0] + 2)
-                        {
-                            match self.at([x, y])
-                            {
-                                Some(tile) => match tile
-                                {
-                                    &Tile::Empty(value) =>
-                                    {
-                                        sum += value;
-                                        count += 1;
-                                    }
-                                    &Tile::Wall => (),
-                                    &Tile::Drain => count += 1,
-                                },
-                                None => (),
-                            }
-                        }
-                    }
-                    Some(Tile::Empty(sum / count as f32))
-                },
-                &Tile::Wall => Some(Tile::Wall),
-                &Tile::Drain => Some(Tile::Drain),
-            },
-            None => None,
-        }
-    }
-
-    fn to_index(point: Point) -> Option<Index>
-    {
-        if point[0] >= 0 && point[1] >= 0 && point[0] < WIDTH as isize && point[1] < HEIGHT as isize
-        {
-            Some([point[0] as usize, point[1] as usize])
+                    _ if !self.contains([point[0] + i as Coordinate, point[1]]) => Tile::Wall,
+                    Some(chunk) => chunk.tile(field, index + i),
+                    None => Tile::Empty(0.0),
+                });
+            }
         }
-        else
+        tiles
+    }
+
+    /// Writes `tiles` over the ones within `bounds`, as read by `tiles`. Only chunks where
+    /// something changed are loaded and flagged as changed.
+    pub fn write(&mut self, bounds: Bounds, tiles: &[Tile]) -> ()
+    {
+        self.write_field(0, bounds, tiles)
+    }
+
+    /// Writes `tiles` over the ones within `bounds`, as read by `field_tiles`.
+    pub fn write_field(&mut self, field: FieldIndex, bounds: Bounds, tiles: &[Tile]) -> ()
+    {
+        assert!(tiles.len() == bounds.len(), "Tiles don't match their bounds.");
+        let mut offset = 0;
+        for (point, run) in runs(bounds)
         {
-            None
+            let tiles = &tiles[offset..(offset + run)];
+            offset += run;
+            if self.field_tiles(field, Bounds::new(point, run, 1)) == tiles
+            {
+                continue;
+            }
+            let position = chunk_of(point);
+            let index = tile_index(point);
+            let extent = self.extent;
+            self.activity.mark(position);
+            let chunk = self.load(position);
+            for (i, &tile) in tiles.iter().enumerate()
+            {
+                if extent.is_none_or(|extent| extent.contains([point[0] + i as Coordinate, point[1]]))
+                {
+                    chunk.set(field, index + i, tile);
+                }
+            }
+        }
+    }
+
+    pub fn activity(&self) -> &activity::Activity
+    {
+        &self.activity
+    }
+
+    /// Quantity of every field, summed over the empty tiles of the loaded chunks in a fixed
+    /// order so that it is the same on every run.
+    pub fn totals(&self) -> Vec<f64>
+    {
+        let mut positions: Vec<&ChunkPosition> = self.chunks.keys().collect();
+        positions.sort_by_key(|position| [position[1], position[0]]);
+        let mut totals = vec![0.0; self.fields.len()];
+        for position in positions
+        {
+            let chunk = &self.chunks[position];
+            for (total, plane) in totals.iter_mut().zip(chunk.values.iter())
+            {
+                *total += plane.iter().zip(chunk.kinds.iter())
+                    .filter(|&(_, &kind)| kind == Kind::Empty)
+                    .map(|(&value, _)| value as f64)
+                    .sum::<f64>();
+            }
         }
+        totals
     }
+
+    /// Forgets which tiles changed, once they have been redrawn.
+    pub fn clear_dirty(&mut self) -> ()
+    {
+        self.activity.clear_dirty();
+    }
+
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct World
 {
     map: Map,
+    pub parameters: Parameters,
+    pub fluid: fluid::Fluid,
     pub entities: entity::EntityContainer,
+    /// What happened to every field in the last step.
+    #[serde(skip)]
+    budgets: Vec<budget::Budget>,
+    /// What brushes added to every field since the last step.
+    #[serde(skip)]
+    brushed: Vec<f64>,
+    #[serde(skip)]
+    previous_positions: Vec<entity::Point>,
 }
 
 impl World
 {
-    pub fn new() -> Self
+    pub fn new(width: usize, height: usize) -> Self
+    {
+        Self::from_map(Map::new(width, height))
+    }
+
+    pub fn with_boundaries(width: usize, height: usize, boundaries: [Boundary; 2]) -> Self
+    {
+        Self::from_map(Map::with_boundaries(width, height, boundaries))
+    }
+
+    pub fn unbounded() -> Self
+    {
+        Self::from_map(Map::unbounded())
+    }
+
+    pub fn from_map(map: Map) -> Self
     {
         Self
         {
-            map: Map::new(),
+            fluid: fluid::Fluid::new(&map),
+            map,
+            parameters: Parameters::default(),
             entities: entity::EntityContainer::new(),
+            budgets: Vec::new(),
+            brushed: Vec::new(),
+            previous_positions: Vec::new(),
         }
     }
 
-    pub fn at(&self, point: Point) -> Option<&Tile>
+    pub fn bounds(&self) -> Bounds
+    {
+        self.map.bounds()
+    }
+
+    pub fn map(&self) -> &Map
+    {
+        &self.map
+    }
+
+    /// The fluid of an unbounded map is fitted to it on the next step, so only the map and the
+    /// fluid themselves have to be intact.
+    pub fn is_consistent(&self) -> bool
+    {
+        self.map.is_consistent() && self.fluid.is_consistent()
+    }
+
+    pub fn at(&self, point: Point) -> Option<Tile>
     {
         self.map.at(point)
     }
 
-    pub fn at_mut(&mut self, point: Point) -> Option<&mut Tile>
+    pub fn at_mut<'a>(&'a mut self, point: Point) -> Option<TileMut<'a>>
     {
         self.map.at_mut(point)
     }
 
+    pub fn clear_dirty(&mut self) -> ()
+    {
+        self.map.clear_dirty();
+    }
+
     pub fn place_entity(&mut self, point: Point) -> ()
     {
         let entity_point = [point[0] as entity::Coordinate + 0.5,
                             point[1] as entity::Coordinate + 0.5];
+        self.map.touch(point);
         self.entities.0.push(entity::Entity::new(entity_point, [0.0, 0.0]));
     }
 
+    pub fn add_field(&mut self, field: Field) -> FieldIndex
+    {
+        self.map.add_field(field)
+    }
+
+    pub fn set_field(&mut self, index: FieldIndex, field: Field) -> ()
+    {
+        self.map.set_field(index, field);
+    }
+
     pub fn brush(&mut self, value: Tile, position: Point, radius: f32)
     {
-        let map_radius = (radius + 0.5) as isize;
-        for y in (position[1] - map_radius)..(position[1] + 1 + map_radius)
+        self.brush_field(0, value, position, radius);
+    }
+
+    /// Paints a circle of tiles with `value`, as seen through `field`. What that adds to or
+    /// takes from every field is tallied in the budgets of the next step.
+    pub fn brush_field(&mut self, field: FieldIndex, value: Tile, position: Point, radius: f32) -> ()
+    {
+        let fields = self.map.fields().len();
+        self.brushed.resize(fields, 0.0);
+        for point in circle(position, radius)
         {
-            for x in (position[0] - map_radius)..(position[0] + 1 + map_radius)
+            let before: Vec<TileValue> = (0..fields)
+                .map(|other| self.map.field_at(other, point).map_or(0.0, |tile| tile.quantity())).collect();
+            if let Some(mut tile) = self.map.field_at_mut(field, point)
             {
-                if (((position[0] - x) as f32).powi(2) +
-                    ((position[1] - y) as f32).powi(2)).sqrt() <= radius
-                {
-                    let position = [x as Coordinate, y as Coordinate];
-                    match self.at_mut(position)
-                    {
-                        Some(tile) => std::mem::swap(tile, &mut value.clone()),
-                        None => (),
-                    }
-                }
+                *tile = value;
+            }
+            for (other, (brushed, &before)) in self.brushed.iter_mut().zip(before.iter()).enumerate()
+            {
+                let after = self.map.field_at(other, point).map_or(0.0, |tile| tile.quantity());
+                *brushed += after as f64 - before as f64;
+            }
+        }
+    }
+
+    /// Fills a circle of tiles with `material`, whatever their kind. Fails without painting
+    /// anything when the material can't be added to the palette.
+    pub fn paint_material(&mut self, material: Material, position: Point, radius: f32) -> Result<(), String>
+    {
+        let index = self.map.material_index(material)?;
+        for point in circle(position, radius)
+        {
+            self.map.set_material(point, index);
+        }
+        Ok(())
+    }
+
+    /// Positions of the entities before the last step, to interpolate between steps.
+    pub fn previous_positions(&self) -> &[entity::Point]
+    {
+        &self.previous_positions
+    }
+
+    /// What happened to every field in the last step, which is nothing before the first one.
+    pub fn budgets(&self) -> &[budget::Budget]
+    {
+        &self.budgets
+    }
+
+    /// Advances the world by `dt` seconds, tallying what every part of the step added to and
+    /// took from every field.
+    pub fn simulate(&mut self, dt: f32) -> ()
+    {
+        // Chunks with entities in them are kept loaded, so the fluid reaches them.
+        for entity in self.entities.0.iter()
+        {
+            self.map.touch([entity.position[0].floor() as Coordinate, entity.position[1].floor() as Coordinate]);
+        }
+        let start = self.map.totals();
+        let mut budgets: Vec<budget::Budget> = start.iter().enumerate().map(|(field, &total)|
+        {
+            let brushed = self.brushed.get(field).cloned().unwrap_or(0.0);
+            budget::Budget
+            {
+                // Fields without a previous step start from where the brushes left them.
+                initial: self.budgets.get(field).map_or(total - brushed, |budget| budget.total),
+                brushed,
+                ..budget::Budget::default()
+            }
+        }).collect();
+        self.brushed.clear();
+
+        self.fluid.fit(&self.map);
+        let carried = match self.parameters.transport
+        {
+            Transport::Fluid => self.fluid.simulate(&mut self.map, self.parameters.cell_size, dt),
+            Transport::Diffusion => vec![budget::Budget::default(); start.len()],
+        };
+        let exchanges = self.map.simulate(&self.parameters, dt);
+        let diffused = self.map.totals();
+        let previous: Vec<entity::Point> = self.entities.0.iter().map(|entity| entity.position).collect();
+        self.entities.simulate(&self.map, &self.fluid, &self.parameters, dt);
+        // Entities that left through an open or fixed-value edge are gone.
+        let map = &self.map;
+        let (entities, previous): (Vec<entity::Entity>, Vec<entity::Point>) = self.entities.0.drain(..)
+            .zip(previous)
+            .filter(|(entity, _)|
+                map.contains([entity.position[0].floor() as Coordinate, entity.position[1].floor() as Coordinate]))
+            .unzip();
+        self.entities.0 = entities;
+        // Entities that wrapped around a periodic edge aren't drawn sweeping across the map.
+        let bounds = self.map.bounds();
+        let half = [bounds.width as f32 / 2.0, bounds.height as f32 / 2.0];
+        self.previous_positions = self.entities.0.iter().zip(previous).map(|(entity, previous)|
+            if (0..2).any(|axis| (entity.position[axis] - previous[axis]).abs() > half[axis])
+            {
+                entity.position
             }
+            else
+            {
+                previous
+            }).collect();
+        self.entities.disturb(&mut self.map, &self.parameters, dt);
+        let end = self.map.totals();
+
+        for (field, budget) in budgets.iter_mut().enumerate()
+        {
+            budget.add(&carried[field]);
+            budget.add(&exchanges[field]);
+            budget.entities = end[field] - diffused[field];
+            budget.total = end[field];
         }
+        self.budgets = budgets;
     }
+}
 
-    pub fn simulate(&mut self) -> ()
+/// The tiles within `radius` of the centre of the tile at `position`.
+fn circle(position: Point, radius: f32) -> Vec<Point>
+{
+    let map_radius = (radius + 0.5) as isize;
+    let mut points = Vec::new();
+    for y in (position[1] - map_radius)..(position[1] + 1 + map_radius)
     {
-        self.map.simulate();
-        self.entities.simulate(&self.map);
+        for x in (position[0] - map_radius)..(position[0] + 1 + map_radius)
+        {
+            if (((position[0] - x) as f32).powi(2) +
+                ((position[1] - y) as f32).powi(2)).sqrt() <= radius
+            {
+                points.push([x as Coordinate, y as Coordinate]);
+            }
+        }
     }
+    points
 }
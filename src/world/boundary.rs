@@ -0,0 +1,282 @@
+use std;
+
+use super::{Map, Tile, TileValue, Coordinate, Point, Bounds, FieldIndex};
+use super::chunk::{ChunkPosition, chunk_of, chunk_bounds};
+
+/// How a bounded map continues past its extent along one axis.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Boundary
+{
+    /// The tiles along the edge are walls.
+    Walled,
+    /// The map wraps around, so whatever leaves one side enters on the opposite one.
+    Periodic,
+    /// Past the edge the field continues with the value of the edge tile, so nothing diffuses
+    /// across it, while the flow and entities leave freely.
+    Open,
+    /// Past the edge the field holds a fixed value (a Dirichlet condition), while the flow and
+    /// entities leave freely.
+    Fixed(TileValue),
+}
+
+impl std::str::FromStr for Boundary
+{
+    type Err = String;
+
+    /// Parses `walled`, `periodic`, `open` or `fixed:VALUE`.
+    fn from_str(name: &str) -> Result<Self, Self::Err>
+    {
+        match name
+        {
+            "walled" => Ok(Boundary::Walled),
+            "periodic" => Ok(Boundary::Periodic),
+            "open" => Ok(Boundary::Open),
+            _ if name.starts_with("fixed:") => name["fixed:".len()..].parse()
+                .map(Boundary::Fixed)
+                .map_err(|_| format!("invalid fixed boundary value in {}", name)),
+            _ => Err(format!("unknown boundary {}", name)),
+        }
+    }
+}
+
+/// Where a point ends up once the boundaries of a map are applied.
+pub(super) enum Resolved
+{
+    Inside(Point),
+    Fixed(TileValue),
+    Outside,
+}
+
+impl Map
+{
+    /// Moves `point` into the map along its periodic axes.
+    pub fn wrap(&self, point: Point) -> Point
+    {
+        match self.extent
+        {
+            Some(extent) => extent.wrap(point, self.boundaries),
+            None => point,
+        }
+    }
+
+    /// What the pressure and entities find at `point` once the boundaries are applied: points
+    /// are wrapped around periodic axes, past an open edge the edge tile continues, and past a
+    /// fixed-value edge there is an empty tile holding that value. Past a walled edge there is
+    /// nothing, as with `at`.
+    pub fn resolve(&self, point: Point) -> Option<Tile>
+    {
+        self.resolve_field(0, point)
+    }
+
+    /// What `field` finds at `point`, as with `resolve` but past the open and fixed-value edges
+    /// of the map following the boundaries of the field.
+    pub fn resolve_field(&self, field: FieldIndex, point: Point) -> Option<Tile>
+    {
+        match self.resolve_point(field, point)
+        {
+            Resolved::Inside(point) => self.field_at(field, point),
+            Resolved::Fixed(value) => Some(Tile::Empty(value)),
+            Resolved::Outside => None,
+        }
+    }
+
+    pub(super) fn resolve_point(&self, field: FieldIndex, point: Point) -> Resolved
+    {
+        let extent = match self.extent
+        {
+            Some(extent) => extent,
+            None => return Resolved::Inside(point),
+        };
+        let mut point = self.wrap(point);
+        let end = [extent.origin[0] + extent.width as Coordinate, extent.origin[1] + extent.height as Coordinate];
+        for axis in 0..2
+        {
+            if point[axis] >= extent.origin[axis] && point[axis] < end[axis]
+            {
+                continue;
+            }
+            match (self.boundaries[axis], self.fields[field].boundaries[axis])
+            {
+                (Boundary::Open, Boundary::Fixed(value)) | (Boundary::Fixed(_), Boundary::Fixed(value)) =>
+                    return Resolved::Fixed(value),
+                (Boundary::Open, _) | (Boundary::Fixed(_), _) =>
+                    point[axis] = point[axis].max(extent.origin[axis]).min(end[axis] - 1),
+                _ => return Resolved::Outside,
+            }
+        }
+        Resolved::Inside(point)
+    }
+
+    /// Loads and activates the chunks along the open and fixed-value edges where a field holds
+    /// a fixed value, so that the value spreads from them.
+    pub(super) fn touch_fixed_edges(&mut self) -> ()
+    {
+        let extent = match self.extent
+        {
+            Some(extent) => extent,
+            None => return,
+        };
+        let fixed: Vec<bool> = (0..2).map(|axis| match self.boundaries[axis]
+        {
+            Boundary::Open | Boundary::Fixed(_) => self.fields.iter()
+                .any(|field| matches!(field.boundaries[axis], Boundary::Fixed(_))),
+            _ => false,
+        }).collect();
+        let size = [extent.width as Coordinate, extent.height as Coordinate];
+        let mut edges = Vec::new();
+        for axis in (0..2).filter(|&axis| fixed[axis])
+        {
+            let across = 1 - axis;
+            for &edge in [extent.origin[axis], extent.origin[axis] + size[axis] - 1].iter()
+            {
+                for offset in 0..size[across]
+                {
+                    let mut point = [0; 2];
+                    point[axis] = edge;
+                    point[across] = extent.origin[across] + offset;
+                    if !edges.contains(&chunk_of(point))
+                    {
+                        edges.push(chunk_of(point));
+                    }
+                }
+            }
+        }
+        for position in edges
+        {
+            self.load(position);
+            self.activity.mark(position);
+        }
+    }
+
+    /// Chunks holding the tiles that those of the chunk at `position` neighbour across a
+    /// periodic edge.
+    pub(super) fn wrapped_neighbours(&self, position: ChunkPosition) -> Vec<ChunkPosition>
+    {
+        let mut neighbours = Vec::new();
+        let extent = match self.extent
+        {
+            Some(extent) => extent,
+            None => return neighbours,
+        };
+        let bounds = match chunk_bounds(position).intersection(&extent)
+        {
+            Some(bounds) => bounds,
+            None => return neighbours,
+        };
+        let ring = Bounds::new([bounds.origin[0] - 1, bounds.origin[1] - 1], bounds.width + 2, bounds.height + 2);
+        if ring.intersection(&extent) == Some(ring)
+        {
+            return neighbours;
+        }
+        for i in 0..ring.len()
+        {
+            let point = ring.point(i);
+            if extent.contains(point)
+            {
+                continue;
+            }
+            let wrapped = self.wrap(point);
+            if extent.contains(wrapped) && !neighbours.contains(&chunk_of(wrapped))
+            {
+                neighbours.push(chunk_of(wrapped));
+            }
+        }
+        neighbours
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use entity::Entity;
+    use world::{World, Boundary, Tile, Transport};
+
+    fn diffusing(width: usize, height: usize, boundaries: [Boundary; 2]) -> World
+    {
+        let mut world = World::with_boundaries(width, height, boundaries);
+        world.parameters.transport = Transport::Diffusion;
+        world
+    }
+
+    fn value(world: &World, point: [isize; 2]) -> f32
+    {
+        world.at(point).unwrap().quantity()
+    }
+
+    #[test]
+    fn periodic_edges_diffuse_into_each_other() -> ()
+    {
+        let mut world = diffusing(40, 30, [Boundary::Periodic, Boundary::Walled]);
+        for y in 0..30
+        {
+            *world.at_mut([0, y]).unwrap() = Tile::Empty(1.0);
+        }
+        assert_eq!(world.map().resolve([-1, 7]), world.at([39, 7]));
+        assert_eq!(world.map().resolve([7, -1]), None);
+        for _ in 0..10
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        assert!(value(&world, [39, 15]) > 0.0);
+        assert!((value(&world, [39, 15]) - value(&world, [1, 15])).abs() < 1e-6);
+        let budget = &world.budgets()[0];
+        assert!((budget.total - 30.0).abs() < 1e-3 && budget.boundaries == 0.0);
+    }
+
+    #[test]
+    fn fixed_edges_feed_their_value_in() -> ()
+    {
+        let mut world = diffusing(40, 30, [Boundary::Fixed(1.0), Boundary::Walled]);
+        assert_eq!(world.map().resolve([-1, 7]), Some(Tile::Empty(1.0)));
+        let mut fed = 0.0;
+        for _ in 0..10
+        {
+            world.simulate(1.0 / 60.0);
+            fed += world.budgets()[0].boundaries;
+        }
+        assert!(value(&world, [0, 15]) > value(&world, [1, 15]) && value(&world, [1, 15]) > 0.0);
+        assert!(value(&world, [20, 15]) < value(&world, [1, 15]));
+        assert!((world.budgets()[0].total - fed).abs() < 1e-3);
+    }
+
+    #[test]
+    fn open_edges_continue_the_edge_tile() -> ()
+    {
+        let mut world = diffusing(40, 30, [Boundary::Open, Boundary::Walled]);
+        *world.at_mut([39, 7]).unwrap() = Tile::Empty(1.0);
+        assert_eq!(world.map().resolve([45, 7]), Some(Tile::Empty(1.0)));
+        for _ in 0..10
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        let budget = &world.budgets()[0];
+        assert!((budget.total - 1.0).abs() < 1e-5 && budget.boundaries == 0.0);
+    }
+
+    #[test]
+    fn fixed_edges_feed_the_flow() -> ()
+    {
+        let mut world = World::with_boundaries(40, 30, [Boundary::Fixed(1.0), Boundary::Walled]);
+        for _ in 0..30
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        assert!(value(&world, [0, 15]) > value(&world, [5, 15]) && value(&world, [5, 15]) > 0.0);
+        assert!(world.budgets()[0].boundaries > 0.0);
+    }
+
+    #[test]
+    fn entities_keep_their_previous_positions_across_edges() -> ()
+    {
+        let mut world = diffusing(40, 30, [Boundary::Periodic, Boundary::Open]);
+        world.entities.0.push(Entity::new([39.5, 15.5], [3.0, 0.0]));
+        world.entities.0.push(Entity::new([20.5, 0.5], [0.0, -3.0]));
+        world.entities.0.push(Entity::new([20.5, 15.5], [0.0, 1.0]));
+        world.simulate(1.0 / 60.0);
+        // The second entity left through the open edge, the first wrapped around.
+        assert_eq!(world.entities.0.len(), 2);
+        let previous = world.previous_positions();
+        assert!(world.entities.0[0].position[0] < 10.0 && previous[0] == world.entities.0[0].position);
+        assert!(previous[1] == [20.5, 15.5] && world.entities.0[1].position[1] > 15.5);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::BTreeSet;
+
+use activity;
+use budget;
+
+use super::{Map, Tile, TileValue, Coordinate, Point, Bounds, Boundary, FieldIndex, MaterialIndex};
+
+/// Edge length of the square chunks a map is stored in, in tiles.
+pub const CHUNK_SIZE: usize = 64;
+/// Updates a blank chunk stays inactive for before it is unloaded.
+const IDLE_UPDATES: u32 = 600;
+
+/// Position of a chunk, in chunks. Chunk `[x, y]` holds the tiles from
+/// `[x * CHUNK_SIZE, y * CHUNK_SIZE]` up to the next chunk.
+pub type ChunkPosition = [Coordinate; 2];
+
+pub fn chunk_of(point: Point) -> ChunkPosition
+{
+    [point[0].div_euclid(CHUNK_SIZE as Coordinate),
+     point[1].div_euclid(CHUNK_SIZE as Coordinate)]
+}
+
+pub fn chunk_bounds(chunk: ChunkPosition) -> Bounds
+{
+    Bounds::new([chunk[0] * CHUNK_SIZE as Coordinate, chunk[1] * CHUNK_SIZE as Coordinate],
+                CHUNK_SIZE, CHUNK_SIZE)
+}
+
+/// Index of `point` within the storage of its chunk.
+pub(super) fn tile_index(point: Point) -> usize
+{
+    (point[1].rem_euclid(CHUNK_SIZE as Coordinate) as usize * CHUNK_SIZE) +
+     point[0].rem_euclid(CHUNK_SIZE as Coordinate) as usize
+}
+
+/// Splits the rows of `bounds` into runs of tiles within a single chunk, given as their first
+/// tile and length.
+pub(super) fn runs(bounds: Bounds) -> Vec<(Point, usize)>
+{
+    let mut runs = Vec::new();
+    for y in 0..bounds.height
+    {
+        let mut x = 0;
+        while x < bounds.width
+        {
+            let point = [bounds.origin[0] + x as Coordinate, bounds.origin[1] + y as Coordinate];
+            let run = (bounds.width - x).min(CHUNK_SIZE - point[0].rem_euclid(CHUNK_SIZE as Coordinate) as usize);
+            runs.push((point, run));
+            x += run;
+        }
+    }
+    runs
+}
+
+/// What occupies a tile, stored apart from its value.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub(super) enum Kind
+{
+    Empty,
+    Wall,
+    Drain,
+    Source,
+    Emitter,
+    Sink,
+}
+
+impl Kind
+{
+    /// Whether tiles of this kind take part in the averages of their neighbours.
+    pub(super) fn is_open(&self) -> bool
+    {
+        match self
+        {
+            &Kind::Wall | &Kind::Emitter | &Kind::Sink => false,
+            &Kind::Empty | &Kind::Drain | &Kind::Source => true,
+        }
+    }
+}
+
+fn to_tile(kind: Kind, value: TileValue) -> Tile
+{
+    match kind
+    {
+        Kind::Empty => Tile::Empty(value),
+        Kind::Wall => Tile::Wall,
+        Kind::Drain => Tile::Drain,
+        Kind::Source => Tile::Source(value),
+        Kind::Emitter => Tile::Emitter(value),
+        Kind::Sink => Tile::Sink(value),
+    }
+}
+
+/// Sources keep their value and emitters and sinks their rate in the value plane; walls and
+/// drains are stored with a value of zero.
+fn from_tile(tile: Tile) -> (Kind, TileValue)
+{
+    match tile
+    {
+        Tile::Empty(value) => (Kind::Empty, value),
+        Tile::Wall => (Kind::Wall, 0.0),
+        Tile::Drain => (Kind::Drain, 0.0),
+        Tile::Source(value) => (Kind::Source, value),
+        Tile::Emitter(rate) => (Kind::Emitter, rate),
+        Tile::Sink(rate) => (Kind::Sink, rate),
+    }
+}
+
+/// Tiles of a chunk, stored as a plane of values per field, a plane of kinds and a plane of
+/// materials so that the diffusion kernel works on contiguous `f32` rows without branching on
+/// the tile kind.
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct Chunk
+{
+    pub(super) values: Vec<Vec<TileValue>>,
+    pub(super) kinds: Vec<Kind>,
+    pub(super) materials: Vec<MaterialIndex>,
+    /// Updates since the chunk was last active or touched.
+    pub(super) idle: u32,
+}
+
+impl Chunk
+{
+    /// A chunk of empty tiles with `fields` value planes, with walls in place of the tiles
+    /// outside `extent`.
+    pub(super) fn new(position: ChunkPosition, extent: Option<Bounds>, fields: usize) -> Self
+    {
+        let bounds = chunk_bounds(position);
+        Self
+        {
+            values: vec![vec![0.0; CHUNK_SIZE * CHUNK_SIZE]; fields],
+            kinds: (0..(CHUNK_SIZE * CHUNK_SIZE)).map(|i| Self::blank_kind(bounds.point(i), extent)).collect(),
+            materials: vec![0; CHUNK_SIZE * CHUNK_SIZE],
+            idle: 0,
+        }
+    }
+
+    fn blank_kind(point: Point, extent: Option<Bounds>) -> Kind
+    {
+        match extent
+        {
+            Some(extent) if !extent.contains(point) => Kind::Wall,
+            _ => Kind::Empty,
+        }
+    }
+
+    pub(super) fn tile(&self, field: FieldIndex, index: usize) -> Tile
+    {
+        to_tile(self.kinds[index], self.values[field][index])
+    }
+
+    /// Stores `tile` as seen through `field`. Changing the kind of a tile resets its values in
+    /// every other field to zero.
+    pub(super) fn set(&mut self, field: FieldIndex, index: usize, tile: Tile) -> ()
+    {
+        let (kind, value) = from_tile(tile);
+        if kind != self.kinds[index]
+        {
+            self.kinds[index] = kind;
+            for plane in self.values.iter_mut()
+            {
+                plane[index] = 0.0;
+            }
+        }
+        self.values[field][index] = value;
+    }
+
+    /// Checks the planes of the chunk, given the number of fields and of materials in the
+    /// palette.
+    pub(super) fn is_consistent(&self, fields: usize, materials: usize) -> bool
+    {
+        self.kinds.len() == CHUNK_SIZE * CHUNK_SIZE && self.materials.len() == self.kinds.len() &&
+        self.values.len() == fields && self.values.iter().all(|plane| plane.len() == self.kinds.len()) &&
+        self.materials.iter().all(|&material| (material as usize) < materials) &&
+        self.values.iter().all(|plane| self.kinds.iter().zip(plane.iter()).all(|(&kind, &value)| match kind
+        {
+            Kind::Wall | Kind::Drain => value == 0.0,
+            _ => true,
+        }))
+    }
+
+    /// Whether the chunk reads the same as a new one, but for values within `thresholds` of
+    /// zero in every field.
+    fn is_blank(&self, position: ChunkPosition, extent: Option<Bounds>, thresholds: &[f32]) -> bool
+    {
+        let bounds = chunk_bounds(position);
+        (0..self.kinds.len()).all(|i|
+            self.kinds[i] == Self::blank_kind(bounds.point(i), extent) && self.materials[i] == 0 &&
+            self.values.iter().zip(thresholds.iter()).all(|(plane, &threshold)| plane[i].abs() <= threshold))
+    }
+
+    /// Whether values of any field on the side of the chunk facing `direction` would diffuse
+    /// into the neighbour there. Any value does, so none is lost to a neighbour that isn't
+    /// loaded.
+    fn reaches(&self, direction: [Coordinate; 2]) -> bool
+    {
+        let side = |offset: Coordinate| match offset
+        {
+            -1 => 0..1,
+            1 => (CHUNK_SIZE - 1)..CHUNK_SIZE,
+            _ => 0..CHUNK_SIZE,
+        };
+        for y in side(direction[1])
+        {
+            for x in side(direction[0])
+            {
+                if self.values.iter().any(|plane| plane[(y * CHUNK_SIZE) + x] != 0.0)
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Map
+{
+    pub(super) fn load(&mut self, position: ChunkPosition) -> &mut Chunk
+    {
+        let (extent, fields) = (self.extent, self.fields.len());
+        let chunk = self.chunks.entry(position).or_insert_with(|| Chunk::new(position, extent, fields));
+        chunk.idle = 0;
+        chunk
+    }
+
+    /// Largest magnitude of every field over the loaded chunks and fixed-value edges, times
+    /// the activity threshold.
+    pub(super) fn thresholds(&self) -> Vec<f32>
+    {
+        let mut largest: Vec<f32> = self.fields.iter().map(|field| field.boundaries.iter()
+            .fold(0.0f32, |largest, &boundary| match boundary
+            {
+                Boundary::Fixed(value) => largest.max(value.abs()),
+                _ => largest,
+            })).collect();
+        for chunk in self.chunks.values()
+        {
+            for (largest, plane) in largest.iter_mut().zip(chunk.values.iter())
+            {
+                *largest = plane.iter().fold(*largest, |largest, value| largest.max(value.abs()));
+            }
+        }
+        largest.iter().map(|largest| largest * activity::THRESHOLD).collect()
+    }
+
+    /// Loads the neighbours of active chunks that values are about to diffuse into, and
+    /// activates the chunks that read from active ones across a periodic edge.
+    pub(super) fn grow(&mut self) -> ()
+    {
+        let mut grown = Vec::new();
+        let mut wrapped = Vec::new();
+        for (&position, chunk) in self.chunks.iter()
+        {
+            if !self.activity.is_active(position)
+            {
+                continue;
+            }
+            wrapped.extend(self.wrapped_neighbours(position));
+            for y in -1..2
+            {
+                for x in -1..2
+                {
+                    let neighbour = [position[0] + x, position[1] + y];
+                    let within = self.extent
+                        .is_none_or(|extent| extent.intersection(&chunk_bounds(neighbour)).is_some());
+                    if within && !self.chunks.contains_key(&neighbour) && chunk.reaches([x, y])
+                    {
+                        grown.push(neighbour);
+                    }
+                }
+            }
+        }
+        for position in grown
+        {
+            self.load(position);
+            self.activity.mark(position);
+        }
+        for position in wrapped
+        {
+            if !self.chunks.contains_key(&position)
+            {
+                self.load(position);
+            }
+            self.activity.activate(position);
+        }
+    }
+
+    /// Bounds of the groups of loaded chunks that touch, diagonally included, merged where
+    /// they overlap, in a fixed order. A bounded map is a single island covering all of it.
+    pub fn islands(&self) -> Vec<Bounds>
+    {
+        if let Some(extent) = self.extent
+        {
+            return vec![extent];
+        }
+        // Flood fills the chunks into the first and last chunk of every island.
+        let mut remaining: BTreeSet<ChunkPosition> = self.chunks.keys().cloned().collect();
+        let mut islands: Vec<(ChunkPosition, ChunkPosition)> = Vec::new();
+        while let Some(&first) = remaining.iter().next()
+        {
+            remaining.remove(&first);
+            let (mut start, mut end) = (first, first);
+            let mut stack = vec![first];
+            while let Some(position) = stack.pop()
+            {
+                start = [start[0].min(position[0]), start[1].min(position[1])];
+                end = [end[0].max(position[0]), end[1].max(position[1])];
+                for y in -1..2
+                {
+                    for x in -1..2
+                    {
+                        let neighbour = [position[0] + x, position[1] + y];
+                        if remaining.remove(&neighbour)
+                        {
+                            stack.push(neighbour);
+                        }
+                    }
+                }
+            }
+            // Islands whose bounds overlap would write over each other, so they are merged.
+            loop
+            {
+                let overlapping = islands.iter().position(|&(other_start, other_end)|
+                    (0..2).all(|axis| start[axis] <= other_end[axis] && other_start[axis] <= end[axis]));
+                match overlapping
+                {
+                    Some(index) =>
+                    {
+                        let (other_start, other_end) = islands.remove(index);
+                        start = [start[0].min(other_start[0]), start[1].min(other_start[1])];
+                        end = [end[0].max(other_end[0]), end[1].max(other_end[1])];
+                    },
+                    None => break,
+                }
+            }
+            islands.push((start, end));
+        }
+        islands.sort_by_key(|&(start, _)| [start[1], start[0]]);
+        islands.into_iter().map(|(start, end)|
+            Bounds::new([start[0] * CHUNK_SIZE as Coordinate, start[1] * CHUNK_SIZE as Coordinate],
+                        (end[0] - start[0] + 1) as usize * CHUNK_SIZE,
+                        (end[1] - start[1] + 1) as usize * CHUNK_SIZE)).collect()
+    }
+
+    /// Unloads chunks that have been inactive for `IDLE_UPDATES` and read the same as new ones,
+    /// tallying the values they held.
+    pub(super) fn unload_idle(&mut self, thresholds: &[f32], budgets: &mut [budget::Budget]) -> ()
+    {
+        let mut unloaded = Vec::new();
+        for (&position, chunk) in self.chunks.iter_mut()
+        {
+            if self.activity.is_active(position)
+            {
+                chunk.idle = 0;
+                continue;
+            }
+            chunk.idle += 1;
+            if chunk.idle == IDLE_UPDATES && chunk.is_blank(position, self.extent, thresholds)
+            {
+                unloaded.push(position);
+            }
+        }
+        for position in unloaded
+        {
+            let chunk = self.chunks.remove(&position).unwrap();
+            for (budget, plane) in budgets.iter_mut().zip(chunk.values.iter())
+            {
+                budget.unloaded += plane.iter().zip(chunk.kinds.iter())
+                    .filter(|&(_, &kind)| kind == Kind::Empty)
+                    .map(|(&value, _)| value as f64)
+                    .sum::<f64>();
+            }
+            self.activity.unload(position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use world::{Map, Field, Tile, Bounds, Parameters};
+    use super::IDLE_UPDATES;
+
+    #[test]
+    fn fields_share_the_kind_of_a_tile() -> ()
+    {
+        let mut map = Map::new(40, 40);
+        let dye = map.add_field(Field::new("dye"));
+        *map.field_at_mut(dye, [3, 4]).unwrap() = Tile::Empty(2.0);
+        *map.at_mut([3, 4]).unwrap() = Tile::Empty(1.0);
+        assert_eq!(map.at([3, 4]), Some(Tile::Empty(1.0)));
+        assert_eq!(map.field_at(dye, [3, 4]), Some(Tile::Empty(2.0)));
+        *map.at_mut([3, 4]).unwrap() = Tile::Wall;
+        assert_eq!(map.field_at(dye, [3, 4]), Some(Tile::Wall));
+        *map.at_mut([3, 4]).unwrap() = Tile::Empty(0.5);
+        assert_eq!(map.field_at(dye, [3, 4]), Some(Tile::Empty(0.0)));
+        assert!(map.is_consistent());
+    }
+
+    #[test]
+    fn writes_read_back_across_chunks() -> ()
+    {
+        let mut map = Map::new(100, 100);
+        let bounds = Bounds::new([50, 55], 30, 20);
+        let tiles: Vec<Tile> = (0..bounds.len()).map(|i| match i % 3
+        {
+            0 => Tile::Empty(i as f32),
+            1 => Tile::Wall,
+            _ => Tile::Source(-(i as f32)),
+        }).collect();
+        map.write(bounds, &tiles);
+        assert!(map.tiles(bounds) == tiles);
+        assert!(map.is_consistent());
+    }
+
+    #[test]
+    fn unloaded_values_are_tallied() -> ()
+    {
+        let mut map = Map::unbounded();
+        // The threshold is relative to the largest value, walled in far away.
+        for y in 3..6
+        {
+            for x in 2..5
+            {
+                *map.at_mut([x, y]).unwrap() = Tile::Wall;
+            }
+        }
+        *map.at_mut([3, 4]).unwrap() = Tile::Empty(1.0);
+        *map.at_mut([320, 30]).unwrap() = Tile::Empty(1e-7);
+        let mut unloaded = 0.0;
+        for _ in 0..IDLE_UPDATES
+        {
+            unloaded += map.simulate(&Parameters::default(), 1.0 / 60.0)[0].unloaded;
+        }
+        assert!(map.bounds().intersection(&Bounds::new([256, 0], 64, 64)).is_none());
+        assert!((unloaded - 1e-7).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,522 @@
+use activity;
+use budget;
+
+use super::{Map, Tile, TileValue, Coordinate, Point, FieldIndex, Parameters, Material};
+use super::boundary::Resolved;
+use super::chunk::{CHUNK_SIZE, ChunkPosition, Kind, chunk_of, chunk_bounds, tile_index};
+
+/// An open tile that diffusion leaves unchanged, whatever flows into or out of it.
+#[derive(Copy, Clone)]
+enum Reservoir
+{
+    Drain(Point),
+    Source,
+    Boundary,
+}
+
+impl Map
+{
+    /// Lets emitters and sinks act for `dt` seconds, then diffuses every field, after the flow
+    /// has advected them if there is one, splitting the step up where a single explicit step
+    /// would be unstable. Only active chunks are diffused, unless most of them are. Returns
+    /// what fixtures, sources, drains, fixed-value edges and unloaded chunks added to and took
+    /// from every field.
+    pub fn simulate(&mut self, parameters: &Parameters, dt: f32) -> Vec<budget::Budget>
+    {
+        if !self.activity.is_started()
+        {
+            self.activity = activity::Activity::new(self.chunks.keys().cloned());
+        }
+        let mut budgets = vec![budget::Budget::default(); self.fields.len()];
+        self.apply_fixtures(dt, &mut budgets);
+        // Substeps are sized for the most diffusive field and material to stay stable.
+        let rate = 3.0 * parameters.diffusion_rate * dt / parameters.cell_size.powi(2);
+        let fastest = self.materials.iter().fold(1.0f32, |fastest, material| fastest.max(material.diffusivity));
+        let rates: Vec<f32> = self.fields.iter().map(|field| rate * field.diffusivity).collect();
+        let substeps = rates.iter().fold(0.0f32, |substeps, &rate| substeps.max((rate * fastest).ceil())).max(1.0);
+        let rates: Vec<f32> = rates.iter().map(|&rate| rate / substeps).collect();
+        let thresholds = self.thresholds();
+        for _ in 0..(substeps as u32)
+        {
+            self.grow();
+            // Chunks are sorted so that their exchanges are tallied in the same order every run.
+            let full = self.activity.is_mostly_active(self.chunks.keys());
+            let mut positions: Vec<ChunkPosition> = self.chunks.keys().cloned()
+                .filter(|&position| full || self.activity.is_active(position)).collect();
+            positions.sort_by_key(|position| [position[1], position[0]]);
+            let averaged = self.average_chunks(&positions, &rates);
+            let mut changed = Vec::new();
+            for (position, (values, exchanges)) in positions.into_iter().zip(averaged)
+            {
+                for (budget, exchange) in budgets.iter_mut().zip(exchanges.iter())
+                {
+                    budget.add(exchange);
+                }
+                let chunk = self.chunks.get_mut(&position).unwrap();
+                if chunk.values.iter().zip(values.iter()).zip(thresholds.iter()).any(|((old, new), &threshold)|
+                    old.iter().zip(new.iter()).any(|(old, new)| (new - old).abs() > threshold))
+                {
+                    changed.push(position);
+                }
+                chunk.values = values;
+            }
+            self.activity.update(&changed);
+        }
+        self.unload_idle(&thresholds, &mut budgets);
+        budgets
+    }
+
+    /// Adds the output of every emitter to the empty tiles among its 8 neighbours, split
+    /// evenly, and lets every sink take values towards zero in proportion to their size.
+    /// Fixtures act on every field at their rate for that field, and are visited in row-major
+    /// order, so sinks sharing neighbours act the same way on every run.
+    fn apply_fixtures(&mut self, dt: f32, budgets: &mut [budget::Budget]) -> ()
+    {
+        let mut fixtures = Vec::new();
+        for (&position, chunk) in self.chunks.iter()
+        {
+            let bounds = chunk_bounds(position);
+            for (i, &kind) in chunk.kinds.iter().enumerate()
+            {
+                if kind == Kind::Emitter || kind == Kind::Sink
+                {
+                    for field in 0..self.fields.len()
+                    {
+                        fixtures.push((field, bounds.point(i), chunk.tile(field, i)));
+                    }
+                }
+            }
+        }
+        fixtures.sort_by_key(|&(field, point, _)| (field, [point[1], point[0]]));
+        for (field, point, fixture) in fixtures
+        {
+            let mut neighbours = Vec::with_capacity(8);
+            for y in -1..2
+            {
+                for x in -1..2
+                {
+                    let neighbour = self.wrap([point[0] + x, point[1] + y]);
+                    if let Some(Tile::Empty(value)) = self.field_at(field, neighbour)
+                    {
+                        neighbours.push((neighbour, value));
+                    }
+                }
+            }
+            let changes: Vec<TileValue> = match fixture
+            {
+                Tile::Emitter(rate) if !neighbours.is_empty() =>
+                    neighbours.iter().map(|_| rate * dt / neighbours.len() as f32).collect(),
+                Tile::Sink(rate) =>
+                {
+                    let available: TileValue = neighbours.iter().map(|&(_, value)| value.abs()).sum();
+                    let fraction = if available > 0.0 { (rate * dt / available).min(1.0) } else { 0.0 };
+                    neighbours.iter().map(|&(_, value)| -value * fraction).collect()
+                },
+                _ => Vec::new(),
+            };
+            for (&(neighbour, value), &change) in neighbours.iter().zip(changes.iter())
+            {
+                if change != 0.0
+                {
+                    budgets[field].fixtures += change as f64;
+                    *self.field_at_mut(field, neighbour).unwrap() = Tile::Empty(value + change);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn average_chunks(&self, positions: &[ChunkPosition], rates: &[f32])
+        -> Vec<(Vec<Vec<TileValue>>, Vec<budget::Budget>)>
+    {
+        self.average_chunks_serially(positions, rates)
+    }
+
+    /// Every chunk only reads from `self`, so they can be computed in any order with identical
+    /// results.
+    #[cfg(feature = "parallel")]
+    fn average_chunks(&self, positions: &[ChunkPosition], rates: &[f32])
+        -> Vec<(Vec<Vec<TileValue>>, Vec<budget::Budget>)>
+    {
+        use rayon::prelude::*;
+
+        positions.par_iter().map(|&position| self.average_chunk(position, rates)).collect()
+    }
+
+    #[cfg(any(test, not(feature = "parallel")))]
+    fn average_chunks_serially(&self, positions: &[ChunkPosition], rates: &[f32])
+        -> Vec<(Vec<Vec<TileValue>>, Vec<budget::Budget>)>
+    {
+        positions.iter().map(|&position| self.average_chunk(position, rates)).collect()
+    }
+
+    /// New values of every field of the chunk at `position`, diffused at their `rates`, and
+    /// what the reservoirs around it exchanged with every field.
+    fn average_chunk(&self, position: ChunkPosition, rates: &[f32]) -> (Vec<Vec<TileValue>>, Vec<budget::Budget>)
+    {
+        rates.iter().enumerate().map(|(field, &rate)|
+            if rate > 0.0
+            {
+                self.average_plane(position, field, rate)
+            }
+            else
+            {
+                (self.chunks[&position].values[field].clone(), budget::Budget::default())
+            }).unzip()
+    }
+
+    /// New values of `field` in the chunk at `position`. Its tiles and their neighbours are
+    /// gathered into a padded block first, so that the 3x3 sums can be accumulated a whole row
+    /// at a time without branching on tile kinds or chunk borders.
+    ///
+    /// Every pair of neighbours exchanges `rate / 9` of their difference, scaled by a
+    /// conductance that is the same in both directions, so whatever one tile loses the other
+    /// gains. For open space this relaxes every tile towards the average of its 3x3 block.
+    fn average_plane(&self, position: ChunkPosition, field: FieldIndex, rate: f32) -> (Vec<TileValue>, budget::Budget)
+    {
+        const PADDED: usize = CHUNK_SIZE + 2;
+        let default = self.materials[0];
+        let mut values = vec![0.0f32; PADDED * PADDED];
+        // Walls, emitters and sinks are impermeable; drains and unloaded tiles count as zero.
+        let mut permeability = vec![default.permeability; PADDED * PADDED];
+        let mut diffusivity = vec![default.diffusivity; PADDED * PADDED];
+        let origin = [position[0] * CHUNK_SIZE as Coordinate - 1, position[1] * CHUNK_SIZE as Coordinate - 1];
+        let mut reservoirs = false;
+        for row in 0..PADDED
+        {
+            let y = origin[1] + row as Coordinate;
+            let mut column = 0;
+            while column < PADDED
+            {
+                let point = [origin[0] + column as Coordinate, y];
+                let run = (PADDED - column).min(CHUNK_SIZE - point[0].rem_euclid(CHUNK_SIZE as Coordinate) as usize);
+                let target = (row * PADDED) + column;
+                if !self.contains(point) || !self.contains([point[0] + run as Coordinate - 1, y])
+                {
+                    for i in 0..run
+                    {
+                        let (tile, material) = match self.resolve_point(field, [point[0] + i as Coordinate, y])
+                        {
+                            Resolved::Inside(point) =>
+                                (self.field_at(field, point), self.material_at(point).unwrap_or(default)),
+                            Resolved::Fixed(value) =>
+                            {
+                                reservoirs = true;
+                                (Some(Tile::Empty(value)), Material::default())
+                            },
+                            Resolved::Outside => (None, default),
+                        };
+                        let (value, open) = match tile
+                        {
+                            Some(Tile::Empty(value)) => (value, true),
+                            Some(Tile::Source(value)) =>
+                            {
+                                reservoirs = true;
+                                (value, true)
+                            },
+                            Some(Tile::Drain) =>
+                            {
+                                reservoirs = true;
+                                (0.0, true)
+                            },
+                            _ => (0.0, false),
+                        };
+                        values[target + i] = value;
+                        permeability[target + i] = if open { material.permeability } else { 0.0 };
+                        diffusivity[target + i] = material.diffusivity;
+                    }
+                }
+                else if let Some(chunk) = self.chunks.get(&chunk_of(point))
+                {
+                    let source = tile_index(point);
+                    values[target..(target + run)].copy_from_slice(&chunk.values[field][source..(source + run)]);
+                    for i in 0..run
+                    {
+                        let kind = chunk.kinds[source + i];
+                        let material = self.materials[chunk.materials[source + i] as usize];
+                        permeability[target + i] = if kind.is_open() { material.permeability } else { 0.0 };
+                        diffusivity[target + i] = material.diffusivity;
+                        reservoirs |= kind == Kind::Drain || kind == Kind::Source;
+                    }
+                }
+                column += run;
+            }
+        }
+
+        let chunk = &self.chunks[&position];
+        let own_values = &chunk.values[field];
+        let mut averaged = own_values.clone();
+        for y in 0..CHUNK_SIZE
+        {
+            let own = ((y + 1) * PADDED) + 1;
+            let mut flux = [0.0f32; CHUNK_SIZE];
+            for dy in 0..3
+            {
+                for dx in 0..3
+                {
+                    if dx == 1 && dy == 1
+                    {
+                        continue;
+                    }
+                    let start = ((y + dy) * PADDED) + dx;
+                    for (x, flux) in flux.iter_mut().enumerate()
+                    {
+                        let (i, j) = (own + x, start + x);
+                        let conductance = permeability[i].min(permeability[j]) * diffusivity[i].min(diffusivity[j]);
+                        *flux += conductance * (values[j] - values[i]);
+                    }
+                }
+            }
+            for (x, &net) in flux.iter().enumerate()
+            {
+                let index = (y * CHUNK_SIZE) + x;
+                if chunk.kinds[index] == Kind::Empty
+                {
+                    averaged[index] = own_values[index] + net * rate / 9.0;
+                }
+            }
+        }
+        let exchanges = if reservoirs
+        {
+            self.exchanges(position, field, &values, &permeability, &diffusivity, rate)
+        }
+        else
+        {
+            budget::Budget::default()
+        };
+        (averaged, exchanges)
+    }
+
+    /// What the drains, sources and fixed-value edges among the tiles `average_plane` gathered
+    /// for the chunk at `position` exchanged with its empty tiles, as the same pairwise fluxes.
+    fn exchanges(&self, position: ChunkPosition, field: FieldIndex, values: &[TileValue], permeability: &[f32],
+                 diffusivity: &[f32], rate: f32) -> budget::Budget
+    {
+        const PADDED: usize = CHUNK_SIZE + 2;
+        let origin = [position[0] * CHUNK_SIZE as Coordinate - 1, position[1] * CHUNK_SIZE as Coordinate - 1];
+        let reservoirs: Vec<Option<Reservoir>> = (0..(PADDED * PADDED)).map(|i|
+        {
+            let point = [origin[0] + (i % PADDED) as Coordinate, origin[1] + (i / PADDED) as Coordinate];
+            match self.resolve_point(field, point)
+            {
+                Resolved::Inside(point) => match self.field_at(field, point)
+                {
+                    Some(Tile::Drain) => Some(Reservoir::Drain(point)),
+                    Some(Tile::Source(_)) => Some(Reservoir::Source),
+                    _ => None,
+                },
+                Resolved::Fixed(_) => Some(Reservoir::Boundary),
+                Resolved::Outside => None,
+            }
+        }).collect();
+        let chunk = &self.chunks[&position];
+        let mut budget = budget::Budget::default();
+        for y in 0..CHUNK_SIZE
+        {
+            for x in 0..CHUNK_SIZE
+            {
+                if chunk.kinds[(y * CHUNK_SIZE) + x] != Kind::Empty
+                {
+                    continue;
+                }
+                let i = ((y + 1) * PADDED) + x + 1;
+                for dy in 0..3
+                {
+                    for dx in 0..3
+                    {
+                        let j = ((y + dy) * PADDED) + x + dx;
+                        let reservoir = match reservoirs[j]
+                        {
+                            Some(reservoir) => reservoir,
+                            None => continue,
+                        };
+                        let conductance = permeability[i].min(permeability[j]) * diffusivity[i].min(diffusivity[j]);
+                        let gained = (conductance * (values[j] - values[i]) * rate / 9.0) as f64;
+                        match reservoir
+                        {
+                            Reservoir::Drain(point) => *budget.drains.entry(point).or_insert(0.0) -= gained,
+                            Reservoir::Source => budget.sources += gained,
+                            Reservoir::Boundary => budget.boundaries += gained,
+                        }
+                    }
+                }
+            }
+        }
+        budget
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use world::{World, Boundary, Field, Tile, Transport};
+
+    fn diffusing() -> World
+    {
+        let mut world = World::new(40, 30);
+        world.parameters.transport = Transport::Diffusion;
+        world
+    }
+
+    #[test]
+    fn emitters_add_their_rate() -> ()
+    {
+        let mut world = diffusing();
+        *world.at_mut([20, 15]).unwrap() = Tile::Emitter(6.0);
+        world.simulate(0.5);
+        let budget = &world.budgets()[0];
+        assert!((budget.fixtures - 3.0).abs() < 1e-5 && (budget.total - 3.0).abs() < 1e-5);
+        assert_eq!(world.at([20, 15]), Some(Tile::Emitter(6.0)));
+    }
+
+    #[test]
+    fn sinks_take_up_to_their_rate() -> ()
+    {
+        let mut world = diffusing();
+        world.brush(Tile::Empty(1.0), [20, 15], 5.0);
+        *world.at_mut([20, 15]).unwrap() = Tile::Sink(2.0);
+        world.simulate(0.25);
+        let budget = &world.budgets()[0];
+        assert!((budget.fixtures + 0.5).abs() < 1e-5);
+        assert!(budget.residual().abs() < 1e-5);
+        // A sink never takes more than there is around it.
+        for _ in 0..20
+        {
+            world.simulate(0.25);
+        }
+        assert!(world.budgets()[0].total >= -1e-5);
+    }
+
+    #[test]
+    fn sources_hold_their_value() -> ()
+    {
+        let mut world = diffusing();
+        *world.at_mut([20, 15]).unwrap() = Tile::Source(1.0);
+        let mut supplied = 0.0;
+        for _ in 0..20
+        {
+            world.simulate(1.0 / 60.0);
+            supplied += world.budgets()[0].sources;
+        }
+        assert_eq!(world.at([20, 15]), Some(Tile::Source(1.0)));
+        assert!(world.at([21, 15]).unwrap().quantity() > 0.0);
+        assert!(supplied > 0.0 && (world.budgets()[0].total - supplied).abs() < 1e-5);
+    }
+
+    #[test]
+    fn fields_diffuse_at_their_own_rates() -> ()
+    {
+        let mut world = diffusing();
+        let dye = world.add_field(Field{diffusivity: 0.25, ..Field::new("dye")});
+        let still = world.add_field(Field{diffusivity: 0.0, ..Field::new("still")});
+        world.brush(Tile::Empty(1.0), [20, 15], 3.0);
+        world.brush_field(dye, Tile::Empty(1.0), [20, 15], 3.0);
+        world.brush_field(still, Tile::Empty(1.0), [20, 15], 3.0);
+        for _ in 0..30
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        let at = |field, point| world.map().field_at(field, point).unwrap().quantity();
+        assert!(at(0, [25, 15]) > at(dye, [25, 15]) && at(dye, [25, 15]) > 0.0);
+        assert_eq!(at(still, [25, 15]), 0.0);
+        assert_eq!(at(still, [20, 15]), 1.0);
+        for budget in world.budgets()
+        {
+            assert!((budget.total - world.budgets()[0].total).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn fields_diffuse_at_their_own_rates_in_the_flow() -> ()
+    {
+        let mut world = World::new(40, 30);
+        let dye = world.add_field(Field{diffusivity: 1.0, ..Field::new("dye")});
+        let still = world.add_field(Field{diffusivity: 0.0, ..Field::new("still")});
+        world.brush(Tile::Empty(1.0), [10, 15], 3.0);
+        world.brush_field(dye, Tile::Empty(1.0), [25, 15], 1.0);
+        world.brush_field(still, Tile::Empty(1.0), [25, 15], 1.0);
+        for _ in 0..30
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        let at = |field, point| world.map().field_at(field, point).unwrap().quantity();
+        assert!(at(dye, [25, 15]) < at(still, [25, 15]));
+        assert!(at(dye, [25, 18]) > at(still, [25, 18]));
+        assert!((world.budgets()[dye].total - world.budgets()[still].total).abs() < 1e-4);
+    }
+
+    #[test]
+    fn activity_follows_the_scale_of_a_field() -> ()
+    {
+        let spread = |value: f32|
+        {
+            let mut world = World::new(192, 128);
+            world.parameters.transport = Transport::Diffusion;
+            world.brush(Tile::Empty(value), [40, 40], 10.0);
+            for _ in 0..100
+            {
+                world.simulate(1.0 / 60.0);
+            }
+            world
+        };
+        let (large, small) = (spread(1.0), spread(1e-6));
+        let bounds = large.bounds();
+        let (large, small) = (large.map().tiles(bounds), small.map().tiles(bounds));
+        for (large, small) in large.iter().zip(small.iter())
+        {
+            assert!((large.quantity() * 1e-6 - small.quantity()).abs() <= 1e-6 * 1e-6);
+        }
+    }
+
+    #[test]
+    fn fields_keep_their_own_boundaries() -> ()
+    {
+        let mut world = World::with_boundaries(40, 30, [Boundary::Open, Boundary::Walled]);
+        world.parameters.transport = Transport::Diffusion;
+        let dye = world.add_field(Field{boundaries: [Boundary::Fixed(1.0), Boundary::Walled], ..Field::new("dye")});
+        for _ in 0..10
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        assert_eq!(world.budgets()[0].total, 0.0);
+        assert!(world.budgets()[dye].total > 0.0 && world.budgets()[dye].boundaries > 0.0);
+        assert!(world.map().field_at(dye, [0, 15]).unwrap().quantity() > 0.0);
+    }
+}
+
+/// Only built with `--features parallel`, where the parallel averaging is the one in use.
+#[cfg(all(test, feature = "parallel"))]
+mod parallel_tests
+{
+    use world::{World, Boundary, Field, Tile, Material, Transport, ChunkPosition};
+
+    #[test]
+    fn parallel_averaging_matches_serial() -> ()
+    {
+        let mut world = World::with_boundaries(200, 150, [Boundary::Periodic, Boundary::Fixed(0.25)]);
+        world.parameters.transport = Transport::Diffusion;
+        world.add_field(Field{diffusivity: 0.5, ..Field::new("dye")});
+        world.brush(Tile::Empty(1.0), [60, 60], 20.0);
+        world.brush(Tile::Drain, [130, 70], 5.0);
+        world.brush_field(1, Tile::Empty(2.0), [150, 100], 15.0);
+        world.paint_material(Material{diffusivity: 0.3, permeability: 0.5, drag: 0.0}, [100, 75], 30.0).unwrap();
+        let rates = [0.5, 0.25];
+        for _ in 0..20
+        {
+            let mut positions: Vec<ChunkPosition> = world.map.chunks.keys().cloned().collect();
+            positions.sort_by_key(|position| [position[1], position[0]]);
+            let parallel = world.map.average_chunks(&positions, &rates);
+            let serial = world.map.average_chunks_serially(&positions, &rates);
+            for ((parallel_values, parallel_budgets), (serial_values, serial_budgets)) in
+                parallel.iter().zip(serial.iter())
+            {
+                assert!(parallel_values == serial_values);
+                for (parallel_budget, serial_budget) in parallel_budgets.iter().zip(serial_budgets.iter())
+                {
+                    assert!(parallel_budget.to_csv() == serial_budget.to_csv());
+                }
+            }
+            world.simulate(1.0 / 60.0);
+        }
+    }
+}
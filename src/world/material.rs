@@ -0,0 +1,214 @@
+use std;
+
+use super::{Map, Point, Bounds};
+use super::chunk::{chunk_of, tile_index, runs};
+
+/// Physical properties of what fills a tile, independently of its kind.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Material
+{
+    /// Scale of the diffusion rate of values within the tile.
+    pub diffusivity: f32,
+    /// Weight of the tile in the averages of its neighbours, from `0.0`, letting no value
+    /// through like a wall, to `1.0` for open space, and the share of the flow let through it.
+    /// Membranes combine a low permeability with a low diffusivity.
+    pub permeability: f32,
+    /// Rate at which the flow and entities within the tile are slowed down, per second.
+    pub drag: f32,
+}
+
+impl Default for Material
+{
+    /// Open space.
+    fn default() -> Self
+    {
+        Self
+        {
+            diffusivity: 1.0,
+            permeability: 1.0,
+            drag: 0.0,
+        }
+    }
+}
+
+impl Material
+{
+    /// Whether the material makes sense, describing what doesn't otherwise.
+    pub fn validate(&self) -> Result<(), String>
+    {
+        if self.diffusivity >= 0.0 && self.drag >= 0.0 && (0.0..=1.0).contains(&self.permeability)
+        {
+            Ok(())
+        }
+        else
+        {
+            Err("a material needs a non-negative diffusivity and drag, and a permeability between 0 and 1"
+                .to_string())
+        }
+    }
+}
+
+/// Position of a material in the palette of a map.
+pub type MaterialIndex = u8;
+
+impl Map
+{
+    pub fn materials(&self) -> &[Material]
+    {
+        &self.materials
+    }
+
+    /// Index of `material` in the palette, which it is added to if it isn't in it yet. Fails
+    /// for invalid materials, and for new ones once the palette is full.
+    pub fn material_index(&mut self, material: Material) -> Result<MaterialIndex, String>
+    {
+        material.validate()?;
+        if let Some(index) = self.materials.iter().position(|&other| other == material)
+        {
+            return Ok(index as MaterialIndex);
+        }
+        if self.materials.len() > MaterialIndex::MAX as usize
+        {
+            return Err(format!("the palette already holds {} materials", self.materials.len()));
+        }
+        self.materials.push(material);
+        Ok((self.materials.len() - 1) as MaterialIndex)
+    }
+
+    /// Material of the tile at `point`, which is the default one for tiles of chunks that
+    /// aren't loaded.
+    pub fn material_at(&self, point: Point) -> Option<Material>
+    {
+        if !self.contains(point)
+        {
+            return None;
+        }
+        match self.chunks.get(&chunk_of(point))
+        {
+            Some(chunk) => Some(self.materials[chunk.materials[tile_index(point)] as usize]),
+            None => Some(self.materials[0]),
+        }
+    }
+
+    /// Fills the tile at `point` with the material at `index` of the palette, flagging it as
+    /// changed like `at_mut` does.
+    pub fn set_material(&mut self, point: Point, index: MaterialIndex) -> ()
+    {
+        assert!((index as usize) < self.materials.len(), "Material isn't in the palette.");
+        if !self.contains(point)
+        {
+            return;
+        }
+        let position = chunk_of(point);
+        self.activity.mark(position);
+        self.load(position).materials[tile_index(point)] = index;
+    }
+
+    /// Reads the materials of the tiles within `bounds` in row-major order, as indices into the
+    /// palette. Tiles outside the map read as the default material.
+    pub fn material_indices(&self, bounds: Bounds) -> Vec<MaterialIndex>
+    {
+        let mut indices = Vec::with_capacity(bounds.len());
+        for (point, run) in runs(bounds)
+        {
+            let index = tile_index(point);
+            match self.chunks.get(&chunk_of(point))
+            {
+                Some(chunk) => indices.extend_from_slice(&chunk.materials[index..(index + run)]),
+                None => indices.extend(std::iter::repeat_n(0, run)),
+            }
+        }
+        indices
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use world::{World, Tile, Transport, Material, Bounds};
+
+    fn spread(material: Material, transport: Transport) -> World
+    {
+        let mut world = World::new(40, 30);
+        world.parameters.transport = transport;
+        let index = world.map.material_index(material).unwrap();
+        for y in 0..30
+        {
+            world.map.set_material([20, y], index);
+        }
+        world.brush(Tile::Empty(1.0), [10, 15], 5.0);
+        for _ in 0..100
+        {
+            world.simulate(1.0 / 60.0);
+        }
+        world
+    }
+
+    fn right_of_membrane(world: &World) -> f32
+    {
+        (21..40).map(|x| world.at([x, 15]).unwrap().quantity()).sum()
+    }
+
+    #[test]
+    fn impermeable_tiles_block_diffusion() -> ()
+    {
+        let world = spread(Material{permeability: 0.0, ..Material::default()}, Transport::Diffusion);
+        assert!(world.at([19, 15]).unwrap().quantity() > 0.0);
+        assert_eq!(right_of_membrane(&world), 0.0);
+        assert!(world.budgets()[0].residual().abs() < 1e-4);
+    }
+
+    #[test]
+    fn membranes_slow_diffusion_down() -> ()
+    {
+        let open = right_of_membrane(&spread(Material::default(), Transport::Diffusion));
+        let membrane = Material{diffusivity: 0.1, permeability: 0.5, drag: 0.0};
+        let membrane = right_of_membrane(&spread(membrane, Transport::Diffusion));
+        assert!(membrane > 0.0 && membrane < 0.5 * open, "{} through the membrane, {} without", membrane, open);
+    }
+
+    #[test]
+    fn impermeable_tiles_block_the_flow() -> ()
+    {
+        let world = spread(Material{permeability: 0.0, ..Material::default()}, Transport::Fluid);
+        assert!(world.at([19, 15]).unwrap().quantity() > 0.0);
+        assert_eq!(right_of_membrane(&world), 0.0);
+    }
+
+    #[test]
+    fn membranes_slow_the_flow_down() -> ()
+    {
+        let open = right_of_membrane(&spread(Material::default(), Transport::Fluid));
+        let membrane = Material{diffusivity: 1.0, permeability: 0.05, drag: 0.0};
+        let membrane = right_of_membrane(&spread(membrane, Transport::Fluid));
+        assert!(membrane > 0.0 && membrane < 0.5 * open, "{} through the membrane, {} without", membrane, open);
+    }
+
+    #[test]
+    fn materials_read_back() -> ()
+    {
+        let mut world = World::new(100, 30);
+        let material = Material{diffusivity: 0.5, permeability: 0.5, drag: 2.0};
+        world.paint_material(material, [63, 10], 2.0).unwrap();
+        assert_eq!(world.map().material_at([64, 10]), Some(material));
+        assert_eq!(world.map().material_at([90, 10]), Some(Material::default()));
+        assert_eq!(world.map().material_at([-1, 10]), None);
+        let indices = world.map().material_indices(Bounds::new([60, 10], 8, 1));
+        assert_eq!(indices, vec![0, 1, 1, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn palettes_reject_invalid_materials() -> ()
+    {
+        let mut world = World::new(10, 10);
+        assert!(world.map.material_index(Material{permeability: 1.5, ..Material::default()}).is_err());
+        assert!(world.map.material_index(Material{drag: -1.0, ..Material::default()}).is_err());
+        for i in 1..256
+        {
+            assert_eq!(world.map.material_index(Material{drag: i as f32, ..Material::default()}), Ok(i as u8));
+        }
+        assert!(world.paint_material(Material{drag: 256.0, ..Material::default()}, [5, 5], 1.0).is_err());
+        assert_eq!(world.map.material_index(Material{drag: 3.0, ..Material::default()}), Ok(3));
+        assert_eq!(world.map().material_at([5, 5]), Some(Material::default()));
+    }
+}